@@ -0,0 +1,26 @@
+use twitch_chat::client::ClientError;
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ChatError
+{
+    NotConnected,
+    Connect(ClientError),
+    Send(ClientError),
+}
+
+pub type ChatResult<T> = Result<T, ChatError>;
+
+impl fmt::Display for ChatError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            ChatError::NotConnected => write!(f, "not connected"),
+            ChatError::Connect(ref e) => write!(f, "could not connect: {}", e),
+            ChatError::Send(ref e) => write!(f, "could not send: {}", e),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use credentials::Credentials;
+use passphrase::{self, SealingKey};
+use session::{config_dir, open_private};
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+const ACCOUNTS_FILE: &'static str = "accounts";
+
+/* Saved logins, kept in a file only the owner can read and encrypted if a
+ * passphrase was set.
+ */
+pub struct CredentialStore
+{
+    accounts: Vec<Credentials>,
+    /* Set while the file is encrypted */
+    key: Option<SealingKey>,
+    /* The encrypted file as read, until the passphrase is given */
+    locked: Option<String>,
+}
+
+impl CredentialStore
+{
+    pub fn load() -> CredentialStore
+    {
+        let mut contents = String::new();
+        if let Some(mut file) = config_dir().map(|dir| dir.join(ACCOUNTS_FILE))
+                                            .and_then(|path| File::open(path).ok())
+        {
+            if file.read_to_string(&mut contents).is_err()
+            {
+                contents.clear();
+            }
+        }
+        if passphrase::is_sealed(&contents)
+        {
+            return CredentialStore
+            {
+                accounts: Vec::new(),
+                key: None,
+                locked: Some(contents),
+            };
+        }
+        CredentialStore::parse(&contents)
+    }
+
+    /* No accounts can be read or saved until unlocked */
+    pub fn is_locked(&self) -> bool
+    {
+        self.locked.is_some()
+    }
+
+    /* False if the passphrase is wrong */
+    pub fn unlock(&mut self, passphrase: &str) -> bool
+    {
+        let opened = match self.locked
+        {
+            Some(ref sealed) => passphrase::open(passphrase, sealed),
+            None => return true,
+        };
+        match opened.and_then(|(key, contents)| String::from_utf8(contents).ok().map(|contents| (key, contents)))
+        {
+            Some((key, contents)) =>
+            {
+                self.accounts = CredentialStore::parse(&contents).accounts;
+                self.key = Some(key);
+                self.locked = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /* Encrypts the file from now on, or stops if passphrase is empty */
+    pub fn set_passphrase(&mut self, passphrase: &str) -> io::Result<()>
+    {
+        self.key = if passphrase.is_empty()
+        {
+            None
+        }
+        else
+        {
+            Some(try!(SealingKey::new(passphrase)))
+        };
+        self.save()
+    }
+
+    pub fn accounts(&self) -> &[Credentials]
+    {
+        &self.accounts
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Credentials>
+    {
+        self.accounts.iter()
+                     .find(|account| account.username.to_lowercase() == username.to_lowercase())
+    }
+
+    /* Replaces the token if the account was already saved */
+    pub fn remember(&mut self, credentials: Credentials) -> io::Result<()>
+    {
+        self.accounts.retain(|account| account.username.to_lowercase() != credentials.username.to_lowercase());
+        self.accounts.push(credentials);
+        self.save()
+    }
+
+    pub fn forget(&mut self, username: &str) -> io::Result<()>
+    {
+        self.accounts.retain(|account| account.username.to_lowercase() != username.to_lowercase());
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        /* Writing now would lose the accounts still encrypted */
+        if self.is_locked()
+        {
+            return Err(io::Error::new(io::ErrorKind::Other, "the saved accounts are locked"));
+        }
+        let contents = match self.key
+        {
+            Some(ref key) => try!(key.seal(self.serialize().as_bytes())),
+            None => self.serialize(),
+        };
+
+        let dir = try!(config_dir().ok_or(io::Error::new(io::ErrorKind::NotFound,
+                                                         "no config directory")));
+        try!(fs::create_dir_all(&dir));
+        let mut file = try!(open_private(dir.join(ACCOUNTS_FILE)));
+        file.write_all(contents.as_bytes())
+    }
+
+    /* One "username=oauth" per line */
+    fn parse(contents: &str) -> CredentialStore
+    {
+        let accounts = contents.lines()
+                               .filter_map(|line| {
+                                   let mut parts = line.splitn(2, '=');
+                                   match (parts.next(), parts.next())
+                                   {
+                                       (Some(username), Some(oauth)) if !username.is_empty() =>
+                                           Some(Credentials::new(username.into(), oauth.into())),
+                                       _ => None,
+                                   }
+                               })
+                               .collect();
+        CredentialStore
+        {
+            accounts: accounts,
+            key: None,
+            locked: None,
+        }
+    }
+
+    fn serialize(&self) -> String
+    {
+        self.accounts.iter()
+                     .map(|account| format!("{}={}\n", account.username, account.oauth))
+                     .collect()
+    }
+}
+
+#[test]
+fn test_credential_store_parse()
+{
+    let store = CredentialStore::parse("onvar=oauth:abc=\nbroken\nbot=oauth:def\n");
+    assert_eq!(store.accounts().len(), 2);
+    assert_eq!(store.get("OnVar").unwrap().oauth, "oauth:abc=");
+    assert_eq!(CredentialStore::parse(&store.serialize()).accounts().len(), 2);
+}
+
+#[test]
+fn test_credential_store_unlock()
+{
+    let sealed = SealingKey::for_tests("hunter2").seal(b"onvar=oauth:abc\n").unwrap();
+    let mut store = CredentialStore
+    {
+        accounts: Vec::new(),
+        key: None,
+        locked: Some(sealed),
+    };
+    assert!(store.is_locked());
+    assert!(store.get("onvar").is_none());
+    assert!(!store.unlock("hunter3"));
+    assert!(store.unlock("hunter2"));
+    assert!(!store.is_locked());
+    assert_eq!(store.get("onvar").unwrap().oauth, "oauth:abc");
+}
@@ -0,0 +1,25 @@
+use twitch_chat::auth::Auth;
+
+#[derive(Clone,Debug)]
+pub struct Credentials
+{
+    pub username: String,
+    pub oauth: String,
+}
+
+impl Credentials
+{
+    pub fn new(username: String, oauth: String) -> Credentials
+    {
+        Credentials
+        {
+            username: username,
+            oauth: oauth,
+        }
+    }
+
+    pub fn to_auth(&self) -> Auth
+    {
+        Auth::new(self.username.clone(), self.oauth.clone())
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* Seconds a cached image is used without asking the server again */
+const TTL_VAR: &'static str = "TWITCHRS_IMAGE_CACHE_TTL";
+const DEFAULT_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/* Files younger than this are left alone by a sweep, as an object may be
+ * written just before the entry pointing to it.
+ */
+pub const SWEEP_GRACE_SECS: u64 = 60 * 60;
+
+/* Keeps temporary file names apart when several threads write at once */
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct CacheEntry
+{
+    pub data: Vec<u8>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /* False once older than the TTL, the data should be revalidated */
+    pub fresh: bool,
+}
+
+/* Images and API lists kept between runs. Contents are stored once under
+ * objects/, named after their hash, and entries/ maps each key (usually a
+ * URL) to its object along with what is needed to revalidate it.
+ */
+#[derive(Clone,Debug)]
+pub struct DiskCache
+{
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl DiskCache
+{
+    pub fn new(dir: PathBuf, ttl: Duration) -> DiskCache
+    {
+        DiskCache
+        {
+            dir: dir,
+            ttl: ttl,
+        }
+    }
+
+    /* Under the user's cache directory, with the TTL from the environment */
+    pub fn from_env() -> Option<DiskCache>
+    {
+        let ttl = env::var(TTL_VAR).ok()
+                                   .and_then(|ttl| u64::from_str(&ttl).ok())
+                                   .unwrap_or(DEFAULT_TTL_SECS);
+        cache_dir().map(|dir| DiskCache::new(dir.join("images"), Duration::from_secs(ttl)))
+    }
+
+    pub fn get(&self, key: &str) -> Option<CacheEntry>
+    {
+        let index = match read_file(self.entry_path(key)).ok()
+                                                         .and_then(|index| String::from_utf8(index).ok())
+        {
+            Some(index) => index,
+            None => return None,
+        };
+
+        let mut stored_key = None;
+        let mut object = None;
+        let mut etag = None;
+        let mut last_modified = None;
+        let mut fetched = 0;
+        for line in index.lines()
+        {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next())
+            {
+                (Some("key"), Some(value)) => stored_key = Some(value),
+                (Some("object"), Some(value)) => object = Some(value),
+                (Some("etag"), Some(value)) => etag = Some(String::from(value)),
+                (Some("last-modified"), Some(value)) => last_modified = Some(String::from(value)),
+                (Some("fetched"), Some(value)) => fetched = u64::from_str(value).unwrap_or(0),
+                _ => (),
+            }
+        }
+
+        /* Two keys could hash alike */
+        if stored_key != Some(key)
+        {
+            return None;
+        }
+        let data = match object.and_then(|object| read_file(self.dir.join("objects").join(object)).ok())
+        {
+            Some(data) => data,
+            None => return None,
+        };
+
+        Some(CacheEntry
+        {
+            data: data,
+            etag: etag,
+            last_modified: last_modified,
+            fresh: now_secs().saturating_sub(fetched) < self.ttl.as_secs(),
+        })
+    }
+
+    pub fn put(&self, key: &str, data: &[u8], etag: Option<&str>, last_modified: Option<&str>) -> io::Result<()>
+    {
+        let object = format!("{:016x}-{}", fnv1a(data), data.len());
+        let object_path = self.dir.join("objects").join(&object);
+        if !object_path.exists()
+        {
+            try!(write_file(object_path, data));
+        }
+
+        let mut index = format!("key={}\nobject={}\nfetched={}\n", key, object, now_secs());
+        if let Some(etag) = etag
+        {
+            index.push_str(&format!("etag={}\n", etag));
+        }
+        if let Some(last_modified) = last_modified
+        {
+            index.push_str(&format!("last-modified={}\n", last_modified));
+        }
+        write_file(self.entry_path(key), index.as_bytes())
+    }
+
+    /* The server said our copy is still good */
+    pub fn refresh(&self, key: &str) -> io::Result<()>
+    {
+        match self.get(key)
+        {
+            Some(entry) => self.put(key,
+                                    &entry.data,
+                                    entry.etag.as_ref().map(String::as_str),
+                                    entry.last_modified.as_ref().map(String::as_str)),
+            None => Ok(()),
+        }
+    }
+
+    /* Removes objects no entry points to any more, left behind when an
+     * entry is replaced, and temporary files of interrupted writes. Returns
+     * how many files went.
+     */
+    pub fn sweep(&self, grace: Duration) -> io::Result<usize>
+    {
+        let mut used = HashSet::new();
+        let mut removed = 0;
+        for entry in try!(fs::read_dir(self.dir.join("entries")))
+        {
+            let entry = try!(entry);
+            if is_temp(&entry)
+            {
+                removed += remove_if_older(&entry, grace);
+                continue;
+            }
+            if let Ok(index) = read_file(entry.path())
+            {
+                for line in String::from_utf8_lossy(&index).lines()
+                {
+                    if line.starts_with("object=")
+                    {
+                        used.insert(String::from(&line["object=".len()..]));
+                    }
+                }
+            }
+        }
+
+        for entry in try!(fs::read_dir(self.dir.join("objects")))
+        {
+            let entry = try!(entry);
+            if !used.contains(&*entry.file_name().to_string_lossy())
+            {
+                removed += remove_if_older(&entry, grace);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf
+    {
+        self.dir.join("entries").join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+}
+
+/* $XDG_CACHE_HOME/twitchrs, falling back to ~/.cache/twitchrs */
+pub fn cache_dir() -> Option<PathBuf>
+{
+    let base = match env::var_os("XDG_CACHE_HOME")
+    {
+        Some(ref dir) if PathBuf::from(dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")),
+    };
+    base.map(|dir| dir.join("twitchrs"))
+}
+
+/* Stable across runs and Rust versions, unlike DefaultHasher */
+fn fnv1a(bytes: &[u8]) -> u64
+{
+    bytes.iter()
+         .fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn now_secs() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH)
+                     .map(|now| now.as_secs())
+                     .unwrap_or(0)
+}
+
+fn read_file(path: PathBuf) -> io::Result<Vec<u8>>
+{
+    let mut contents = Vec::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut contents));
+    Ok(contents)
+}
+
+/* Left by write_file when it did not get to the rename */
+fn is_temp(entry: &fs::DirEntry) -> bool
+{
+    entry.file_name().to_string_lossy().contains(".tmp")
+}
+
+fn remove_if_older(entry: &fs::DirEntry, age: Duration) -> usize
+{
+    /* Modified in the future counts as new */
+    let old = entry.metadata().and_then(|metadata| metadata.modified())
+                              .ok()
+                              .and_then(|modified| modified.elapsed().ok())
+                              .map(|elapsed| elapsed >= age)
+                              .unwrap_or(false);
+    if old && fs::remove_file(entry.path()).is_ok()
+    {
+        1
+    }
+    else
+    {
+        0
+    }
+}
+
+/* Written next to the target and renamed over it, so readers never see
+ * half a file.
+ */
+fn write_file(path: PathBuf, contents: &[u8]) -> io::Result<()>
+{
+    if let Some(dir) = path.parent()
+    {
+        try!(fs::create_dir_all(dir));
+    }
+    let temp = path.with_extension(format!("tmp{}-{}", process::id(), TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    {
+        let mut file = try!(File::create(&temp));
+        try!(file.write_all(contents));
+    }
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+fn test_cache(name: &str, ttl: u64) -> DiskCache
+{
+    let dir = env::temp_dir().join(format!("twitchrs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    DiskCache::new(dir, Duration::from_secs(ttl))
+}
+
+#[test]
+fn test_disk_cache_round_trip()
+{
+    let cache = test_cache("round-trip", 60);
+    assert!(cache.get("https://example.com/a.png").is_none());
+
+    cache.put("https://example.com/a.png", b"png", Some("\"abc\""), Some("Mon, 01 Jan 2018 00:00:00 GMT")).unwrap();
+    let entry = cache.get("https://example.com/a.png").unwrap();
+    assert_eq!(entry.data, b"png".to_vec());
+    assert_eq!(entry.etag, Some("\"abc\"".into()));
+    assert_eq!(entry.last_modified, Some("Mon, 01 Jan 2018 00:00:00 GMT".into()));
+    assert!(entry.fresh);
+    assert!(cache.get("https://example.com/b.png").is_none());
+}
+
+#[test]
+fn test_disk_cache_expires_and_shares_objects()
+{
+    let cache = test_cache("expires", 0);
+    cache.put("a", b"same", None, None).unwrap();
+    cache.put("b", b"same", None, None).unwrap();
+    assert!(!cache.get("a").unwrap().fresh);
+    assert_eq!(fs::read_dir(cache.dir.join("objects")).unwrap().count(), 1);
+    assert_eq!(fs::read_dir(cache.dir.join("entries")).unwrap().count(), 2);
+}
+
+#[test]
+fn test_disk_cache_sweep()
+{
+    let cache = test_cache("sweep", 60);
+    cache.put("a", b"old", None, None).unwrap();
+    cache.put("a", b"new", None, None).unwrap();
+    cache.put("b", b"kept", None, None).unwrap();
+    assert_eq!(fs::read_dir(cache.dir.join("objects")).unwrap().count(), 3);
+
+    assert_eq!(cache.sweep(Duration::from_secs(60)).unwrap(), 0);
+    assert_eq!(cache.sweep(Duration::from_secs(0)).unwrap(), 1);
+    assert_eq!(fs::read_dir(cache.dir.join("objects")).unwrap().count(), 2);
+    assert_eq!(cache.get("a").unwrap().data, b"new".to_vec());
+    assert_eq!(cache.get("b").unwrap().data, b"kept".to_vec());
+}
@@ -0,0 +1,404 @@
+use twitch_message::{TwitchCheer, TwitchEmote, TwitchEmoteRange};
+
+use std::str::FromStr;
+
+/* Global cheermotes, lowercase. Any of these followed by an amount is a cheer. */
+const CHEER_PREFIXES: [&'static str; 27] = [
+    "cheer", "doodlecheer", "biblethump", "cheerwhal", "corgo", "uni", "showlove",
+    "party", "seemsgood", "pride", "kappa", "frankerz", "heyguys", "dansgame",
+    "elegiggle", "trihard", "kreygasm", "4head", "swiftrage", "notlikethis",
+    "failfish", "vohiyo", "pjsalt", "mrdestructoid", "bday", "ripcheer", "shamrock",
+];
+
+/* One piece of a regex emote code like ":-?\)" */
+#[derive(Clone,Debug)]
+enum Node
+{
+    Char(char),
+    Any,
+    Class(Vec<char>),
+    /* Alternatives, each a sequence */
+    Group(Vec<Vec<Node>>),
+    Optional(Box<Node>),
+    Star(Box<Node>),
+    /* Matches nothing, but only past the given position. Put after each
+     * repeat of a star so an inner node that can match empty doesn't loop.
+     */
+    Advanced(usize),
+}
+
+#[derive(Clone,Debug)]
+enum Pattern
+{
+    Literal(String),
+    Regex(Vec<Node>),
+}
+
+/* Finds emote codes in text we typed, the way Twitch does for viewers:
+ * only whole space separated words, and global emotes like ":)" or "<3"
+ * whose codes are regexes.
+ */
+pub struct EmoteMatcher
+{
+    patterns: Vec<(u64, Pattern)>,
+}
+
+impl EmoteMatcher
+{
+    pub fn new(emotes: &[TwitchEmote]) -> EmoteMatcher
+    {
+        let patterns = emotes.iter()
+                             .filter(|emote| !emote.code.is_empty())
+                             .map(|emote| (emote.id, compile(&emote.code)))
+                             .collect();
+        EmoteMatcher
+        {
+            patterns: patterns,
+        }
+    }
+
+    /* Ranges in the form of the emotes tag, inclusive code point offsets */
+    pub fn find(&self, message: &str) -> Vec<TwitchEmoteRange>
+    {
+        let mut found: Vec<TwitchEmoteRange> = Vec::new();
+        for (start, word) in words(message)
+        {
+            let matched = self.patterns.iter()
+                                       .find(|&&(_, ref pattern)| matches(pattern, &word))
+                                       .map(|&(id, _)| id);
+            if let Some(id) = matched
+            {
+                let range = (start, start + word.len() - 1);
+                match found.iter_mut().find(|emote| emote.id == id)
+                {
+                    Some(emote) =>
+                    {
+                        emote.ranges.push(range);
+                        continue;
+                    },
+                    None => (),
+                }
+                found.push(TwitchEmoteRange { id: id, ranges: vec![range] });
+            }
+        }
+        found
+    }
+}
+
+/* Words like "Cheer100". Only meaningful in messages with a bits tag. */
+pub fn find_cheers(message: &str) -> Vec<TwitchCheer>
+{
+    words(message).into_iter()
+                  .filter_map(|(start, word)| {
+                      let lower = word.iter()
+                                      .collect::<String>()
+                                      .to_lowercase();
+                      CHEER_PREFIXES.iter()
+                                    .filter(|prefix| lower.starts_with(*prefix))
+                                    .filter_map(|prefix| {
+                                        let amount = &lower[prefix.len()..];
+                                        if amount.is_empty() || !amount.chars().all(|c| c.is_digit(10))
+                                        {
+                                            return None;
+                                        }
+                                        match u64::from_str(amount)
+                                        {
+                                            Ok(amount) if amount > 0 => Some(TwitchCheer {
+                                                prefix: String::from(*prefix),
+                                                amount: amount,
+                                                range: (start, start + word.len()),
+                                            }),
+                                            _ => None,
+                                        }
+                                    })
+                                    .next()
+                  })
+                  .collect()
+}
+
+/* Space separated words with the code point offset each starts at */
+fn words(message: &str) -> Vec<(usize, Vec<char>)>
+{
+    let mut words = Vec::new();
+    let mut current: Option<(usize, Vec<char>)> = None;
+    for (idx, c) in message.chars().enumerate()
+    {
+        if c == ' '
+        {
+            if let Some(word) = current.take()
+            {
+                words.push(word);
+            }
+        }
+        else
+        {
+            current.get_or_insert_with(|| (idx, Vec::new())).1.push(c);
+        }
+    }
+    if let Some(word) = current
+    {
+        words.push(word);
+    }
+    words
+}
+
+fn matches(pattern: &Pattern, word: &[char]) -> bool
+{
+    match *pattern
+    {
+        Pattern::Literal(ref code) => code.chars().eq(word.iter().cloned()),
+        Pattern::Regex(ref nodes) => match_here(nodes, word, 0),
+    }
+}
+
+/* Codes made of plain word characters are compared as they are. Anything
+ * else is a regex, falling back to a literal if it does not parse.
+ */
+fn compile(code: &str) -> Pattern
+{
+    if code.chars().all(|c| c.is_alphanumeric() || c == '_')
+    {
+        return Pattern::Literal(code.into());
+    }
+
+    /* The API escapes "<" and ">" in codes like "\&lt\;3" */
+    let code = code.replace("\\&lt\\;", "<")
+                   .replace("\\&gt\\;", ">")
+                   .replace("&lt;", "<")
+                   .replace("&gt;", ">");
+    let chars: Vec<char> = code.chars().collect();
+    let mut pos = 0;
+    match parse_sequence(&chars, &mut pos)
+    {
+        Some(ref alternatives) if pos == chars.len() && alternatives.len() == 1 =>
+            Pattern::Regex(alternatives[0].clone()),
+        Some(alternatives) if pos == chars.len() =>
+            Pattern::Regex(vec![Node::Group(alternatives)]),
+        _ => Pattern::Literal(code.clone()),
+    }
+}
+
+/* Parses up to a closing ')' or the end, split on '|' */
+fn parse_sequence(chars: &[char], pos: &mut usize) -> Option<Vec<Vec<Node>>>
+{
+    let mut alternatives = Vec::new();
+    let mut sequence: Vec<Node> = Vec::new();
+    while *pos < chars.len()
+    {
+        let c = chars[*pos];
+        *pos += 1;
+        let node = match c
+        {
+            ')' =>
+            {
+                *pos -= 1;
+                break;
+            },
+            '|' =>
+            {
+                alternatives.push(sequence);
+                sequence = Vec::new();
+                continue;
+            },
+            '?' | '*' | '+' =>
+            {
+                let last = match sequence.pop()
+                {
+                    Some(last) => last,
+                    None => return None,
+                };
+                match c
+                {
+                    '?' => sequence.push(Node::Optional(Box::new(last))),
+                    '*' => sequence.push(Node::Star(Box::new(last))),
+                    _ =>
+                    {
+                        sequence.push(last.clone());
+                        sequence.push(Node::Star(Box::new(last)));
+                    },
+                }
+                continue;
+            },
+            '\\' =>
+            {
+                let escaped = match chars.get(*pos)
+                {
+                    Some(&escaped) => escaped,
+                    None => return None,
+                };
+                *pos += 1;
+                Node::Char(escaped)
+            },
+            '.' => Node::Any,
+            '[' =>
+            {
+                let mut class = Vec::new();
+                loop
+                {
+                    match chars.get(*pos)
+                    {
+                        Some(&']') => break,
+                        Some(&'\\') =>
+                        {
+                            match chars.get(*pos + 1)
+                            {
+                                Some(&escaped) => class.push(escaped),
+                                None => return None,
+                            }
+                            *pos += 1;
+                        },
+                        Some(&member) => class.push(member),
+                        None => return None,
+                    }
+                    *pos += 1;
+                }
+                *pos += 1;
+                Node::Class(class)
+            },
+            '(' =>
+            {
+                let group = match parse_sequence(chars, pos)
+                {
+                    Some(group) => group,
+                    None => return None,
+                };
+                if chars.get(*pos) != Some(&')')
+                {
+                    return None;
+                }
+                *pos += 1;
+                Node::Group(group)
+            },
+            other => Node::Char(other),
+        };
+        sequence.push(node);
+    }
+    alternatives.push(sequence);
+    Some(alternatives)
+}
+
+/* Backtracking match of nodes against the whole of input from pos */
+fn match_here(nodes: &[Node], input: &[char], pos: usize) -> bool
+{
+    let (node, rest) = match nodes.split_first()
+    {
+        Some(split) => split,
+        None => return pos == input.len(),
+    };
+    match *node
+    {
+        Node::Char(c) => input.get(pos) == Some(&c) && match_here(rest, input, pos + 1),
+        Node::Any => pos < input.len() && match_here(rest, input, pos + 1),
+        Node::Class(ref class) =>
+            input.get(pos).map(|c| class.contains(c)).unwrap_or(false) && match_here(rest, input, pos + 1),
+        Node::Group(ref alternatives) =>
+        {
+            alternatives.iter()
+                        .any(|alternative| {
+                            let mut sequence = alternative.clone();
+                            sequence.extend_from_slice(rest);
+                            match_here(&sequence, input, pos)
+                        })
+        },
+        Node::Optional(ref inner) =>
+        {
+            let mut sequence = vec![(**inner).clone()];
+            sequence.extend_from_slice(rest);
+            match_here(&sequence, input, pos) || match_here(rest, input, pos)
+        },
+        Node::Star(ref inner) =>
+        {
+            /* One more, then the star again, as long as that one used something */
+            let mut sequence = vec![(**inner).clone(), Node::Advanced(pos), node.clone()];
+            sequence.extend_from_slice(rest);
+            (pos < input.len() && match_here(&sequence, input, pos)) || match_here(rest, input, pos)
+        },
+        Node::Advanced(start) => pos > start && match_here(rest, input, pos),
+    }
+}
+
+#[cfg(test)]
+fn emote(id: u64, code: &str) -> TwitchEmote
+{
+    TwitchEmote { id: id, code: code.into() }
+}
+
+#[test]
+fn test_emote_match_whole_words()
+{
+    let matcher = EmoteMatcher::new(&[emote(25, "Kappa"), emote(1902, "Keepo")]);
+    let found = matcher.find("Kappa KappaPride aKappa Keepo  Kappa");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].id, 25);
+    assert_eq!(found[0].ranges, vec![(0, 4), (31, 35)]);
+    assert_eq!(found[1].id, 1902);
+    assert_eq!(found[1].ranges, vec![(24, 28)]);
+}
+
+#[test]
+fn test_emote_match_regex_codes()
+{
+    let matcher = EmoteMatcher::new(&[emote(1, ":-?\\)"),
+                                      emote(9, "\\&lt\\;3"),
+                                      emote(6, "[oO](_|\\.)[oO]"),
+                                      emote(5, ":-?[z|Z|\\|]"),
+                                      emote(10, ":-?[\\\\/]"),
+                                      emote(12, ":-?(p|P)")]);
+    let ids = |message: &str| matcher.find(message)
+                                     .iter()
+                                     .map(|emote| emote.id)
+                                     .collect::<Vec<_>>();
+    assert_eq!(ids(":)"), vec![1]);
+    assert_eq!(ids(":-)"), vec![1]);
+    assert!(ids(":-))").is_empty());
+    assert!(ids("a:)").is_empty());
+    assert_eq!(ids("<3"), vec![9]);
+    assert_eq!(ids("o_O"), vec![6]);
+    assert_eq!(ids("O.o"), vec![6]);
+    assert!(ids("oxo").is_empty());
+    assert_eq!(ids(":|"), vec![5]);
+    assert_eq!(ids(":-Z"), vec![5]);
+    assert_eq!(ids(":\\"), vec![10]);
+    assert_eq!(ids(":/"), vec![10]);
+    assert_eq!(ids(":P"), vec![12]);
+}
+
+#[test]
+fn test_emote_match_offsets_are_code_points()
+{
+    let matcher = EmoteMatcher::new(&[emote(25, "Kappa")]);
+    let message = "\u{1F600} Kappa";
+    let found = matcher.find(message);
+    assert_eq!(found[0].ranges, vec![(2, 6)]);
+    assert_eq!(found[0].char_ranges(message), vec![(2, 7)]);
+}
+
+#[test]
+fn test_find_cheers()
+{
+    let cheers = find_cheers("Cheer100 hi cheer1 DoodleCheer5000 Cheer Cheer0 aCheer10 Cheer10x \u{1F600} RIPCheer2");
+    let found: Vec<_> = cheers.iter()
+                              .map(|cheer| (cheer.prefix.as_str(), cheer.amount, cheer.range))
+                              .collect();
+    assert_eq!(found, vec![("cheer", 100, (0, 8)),
+                           ("cheer", 1, (12, 18)),
+                           ("doodlecheer", 5000, (19, 34)),
+                           ("ripcheer", 2, (68, 77))]);
+}
+
+#[test]
+fn test_emote_match_star_of_empty()
+{
+    let matcher = EmoteMatcher::new(&[emote(1, "x(a|)*y"), emote(2, "(b?)*")]);
+    let found = matcher.find("xaay xy xaby bb");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].ranges, vec![(0, 3), (5, 6)]);
+    assert_eq!(found[1].ranges, vec![(13, 14)]);
+}
+
+#[test]
+fn test_emote_match_bad_regex_is_literal()
+{
+    let matcher = EmoteMatcher::new(&[emote(1, "(:")]);
+    assert_eq!(matcher.find("(:").len(), 1);
+}
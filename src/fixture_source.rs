@@ -0,0 +1,80 @@
+use image_source::ImageSource;
+use twitch_message::{TwitchBadge, TwitchEmote};
+use twitch_image_loader::{ImageError, ImageResult};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/* An ImageSource for tests. Answers from what the test put in and records
+ * what was asked for.
+ */
+#[derive(Debug,Default)]
+pub struct FixtureSource
+{
+    pub badge_sets: Vec<(TwitchBadge, String)>,
+    /* By channel login */
+    pub channel_badges: HashMap<String, Vec<(TwitchBadge, String)>>,
+    pub emote_sets: HashMap<u64, Vec<TwitchEmote>>,
+    /* By URL, "emote/<id>" or "cheermote/<prefix>/<tier>". Anything else is
+     * NotFound.
+     */
+    pub images: HashMap<String, Vec<u8>>,
+    /* Handed out in order instead of the answer, whatever is asked */
+    pub failures: Mutex<Vec<ImageError>>,
+    pub requests: Mutex<Vec<String>>,
+}
+
+impl FixtureSource
+{
+    pub fn requests(&self) -> Vec<String>
+    {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn answer<T, F>(&self, request: String, found: F) -> ImageResult<T>
+        where F: FnOnce() -> Option<T>
+    {
+        self.requests.lock().unwrap().push(request);
+        let mut failures = self.failures.lock().unwrap();
+        if !failures.is_empty()
+        {
+            return Err(failures.remove(0));
+        }
+        found().ok_or(ImageError::NotFound)
+    }
+}
+
+impl ImageSource for FixtureSource
+{
+    fn badge_sets(&self) -> ImageResult<Vec<(TwitchBadge, String)>>
+    {
+        self.answer(String::from("badges"), || Some(self.badge_sets.clone()))
+    }
+
+    fn channel_badges(&self, channel: &str) -> ImageResult<Vec<(TwitchBadge, String)>>
+    {
+        self.answer(format!("badges/{}", channel), || self.channel_badges.get(channel).cloned())
+    }
+
+    fn emote_set(&self, emote_set: u64) -> ImageResult<Vec<TwitchEmote>>
+    {
+        self.answer(format!("emote-set/{}", emote_set), || self.emote_sets.get(&emote_set).cloned())
+    }
+
+    fn emote(&self, emote_id: u64) -> ImageResult<Vec<u8>>
+    {
+        let name = format!("emote/{}", emote_id);
+        self.answer(name.clone(), || self.images.get(&name).cloned())
+    }
+
+    fn cheermote(&self, prefix: &str, tier: u64) -> ImageResult<Vec<u8>>
+    {
+        let name = format!("cheermote/{}/{}", prefix, tier);
+        self.answer(name.clone(), || self.images.get(&name).cloned())
+    }
+
+    fn image(&self, url: &str) -> ImageResult<Vec<u8>>
+    {
+        self.answer(String::from(url), || self.images.get(url).cloned())
+    }
+}
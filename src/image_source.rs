@@ -0,0 +1,27 @@
+use twitch_message::{TwitchBadge, TwitchEmote};
+use twitch_image_loader::ImageResult;
+
+use std::fmt;
+
+/* Where the image loader gets badges, emotes and their images from. Called
+ * from the loader's worker threads, which do the caching, retrying and
+ * coalescing of requests.
+ */
+pub trait ImageSource: fmt::Debug + Send + Sync
+{
+    /* Global badges with the URLs of their images */
+    fn badge_sets(&self) -> ImageResult<Vec<(TwitchBadge, String)>>;
+
+    /* Subscriber badges of a channel, by login */
+    fn channel_badges(&self, channel: &str) -> ImageResult<Vec<(TwitchBadge, String)>>;
+
+    fn emote_set(&self, emote_set: u64) -> ImageResult<Vec<TwitchEmote>>;
+
+    fn emote(&self, emote_id: u64) -> ImageResult<Vec<u8>>;
+
+    /* Lowercase prefix and tier */
+    fn cheermote(&self, prefix: &str, tier: u64) -> ImageResult<Vec<u8>>;
+
+    /* A badge image, by one of the URLs the badge lists gave */
+    fn image(&self, url: &str) -> ImageResult<Vec<u8>>;
+}
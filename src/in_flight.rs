@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+/* Everyone waiting on a fetch by key, so asking for the same thing again
+ * while it downloads joins the first request instead of starting another.
+ */
+#[derive(Debug)]
+pub struct InFlight<K, V>
+{
+    waiting: Mutex<HashMap<K, Vec<Sender<V>>>>,
+}
+
+impl<K: Eq + Hash, V: Clone> InFlight<K, V>
+{
+    pub fn new() -> InFlight<K, V>
+    {
+        InFlight
+        {
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /* True when nothing was fetching key yet. The caller then has to fetch
+     * it and call finish, which answers every waiter.
+     */
+    pub fn join(&self, key: K, waiter: Sender<V>) -> bool
+    {
+        let mut waiting = self.waiting.lock().unwrap();
+        let waiters = waiting.entry(key).or_insert_with(Vec::new);
+        waiters.push(waiter);
+        waiters.len() == 1
+    }
+
+    pub fn finish(&self, key: &K, value: V)
+    {
+        /* Sent after letting go of the lock */
+        let waiters = self.waiting.lock().unwrap().remove(key);
+        for waiter in waiters.unwrap_or(Vec::new())
+        {
+            /* The tab that asked may be gone by now */
+            let _ = waiter.send(value.clone());
+        }
+    }
+}
+
+#[test]
+fn test_in_flight_coalesces_waiters()
+{
+    use std::sync::mpsc;
+
+    let in_flight = InFlight::new();
+    let (first_tx, first_rx) = mpsc::channel();
+    let (second_tx, second_rx) = mpsc::channel();
+    let (other_tx, other_rx) = mpsc::channel();
+    assert!(in_flight.join(25, first_tx));
+    assert!(!in_flight.join(25, second_tx));
+    assert!(in_flight.join(1902, other_tx));
+
+    in_flight.finish(&25, vec![1, 2, 3]);
+    assert_eq!(first_rx.recv().unwrap(), vec![1, 2, 3]);
+    assert_eq!(second_rx.recv().unwrap(), vec![1, 2, 3]);
+    assert!(other_rx.try_recv().is_err());
+
+    /* Finished keys start over */
+    let (again_tx, again_rx) = mpsc::channel();
+    assert!(in_flight.join(25, again_tx));
+    in_flight.finish(&25, vec![]);
+    assert_eq!(again_rx.recv().unwrap(), vec![]);
+}
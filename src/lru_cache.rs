@@ -0,0 +1,165 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+
+/* How many bytes a cached value keeps alive */
+pub trait Weigh
+{
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for Vec<u8>
+{
+    fn weight(&self) -> usize
+    {
+        self.len()
+    }
+}
+
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct CacheStats
+{
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for CacheStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{} entries, {} KiB, {} hits, {} misses, {} evictions",
+               self.entries, self.bytes / 1024, self.hits, self.misses, self.evictions)
+    }
+}
+
+/* Drops the least recently used values once their total weight goes over
+ * the capacity.
+ */
+#[derive(Debug)]
+pub struct LruCache<K, V>
+{
+    capacity: usize,
+    bytes: usize,
+    /* Bumped on every use, lower is older */
+    tick: u64,
+    values: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+    stats: CacheStats,
+}
+
+impl<K: Clone + Eq + Hash, V: Weigh> LruCache<K, V>
+{
+    pub fn new(capacity: usize) -> LruCache<K, V>
+    {
+        LruCache
+        {
+            capacity: capacity,
+            bytes: 0,
+            tick: 0,
+            values: HashMap::new(),
+            order: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V>
+    {
+        self.tick += 1;
+        match self.values.get_mut(key)
+        {
+            Some(&mut (ref value, ref mut used)) =>
+            {
+                self.order.remove(used);
+                *used = self.tick;
+                self.order.insert(self.tick, key.clone());
+                self.stats.hits += 1;
+                Some(value)
+            },
+            None =>
+            {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    /* Values bigger than the whole cache are not kept */
+    pub fn insert(&mut self, key: K, value: V)
+    {
+        self.remove(&key);
+        let weight = value.weight();
+        if weight > self.capacity
+        {
+            return;
+        }
+
+        while self.bytes + weight > self.capacity
+        {
+            let oldest = match self.order.keys().next()
+            {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(evicted) = self.order.remove(&oldest)
+            {
+                self.remove(&evicted);
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.tick += 1;
+        self.bytes += weight;
+        self.order.insert(self.tick, key.clone());
+        self.values.insert(key, (value, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V>
+    {
+        self.values.remove(key).map(|(value, used)| {
+            self.order.remove(&used);
+            self.bytes -= value.weight();
+            value
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        CacheStats
+        {
+            entries: self.values.len(),
+            bytes: self.bytes,
+            ..self.stats
+        }
+    }
+}
+
+#[test]
+fn test_lru_cache_evicts_least_recently_used()
+{
+    let mut cache = LruCache::new(10);
+    cache.insert("a", vec![0; 4]);
+    cache.insert("b", vec![0; 4]);
+    assert!(cache.get(&"a").is_some());
+    cache.insert("c", vec![0; 4]);
+    assert!(cache.get(&"b").is_none());
+    assert!(cache.get(&"a").is_some());
+    assert!(cache.get(&"c").is_some());
+    assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1, evictions: 1, entries: 2, bytes: 8 });
+}
+
+#[test]
+fn test_lru_cache_replaces_and_skips_oversized()
+{
+    let mut cache = LruCache::new(10);
+    cache.insert(1, vec![0; 6]);
+    cache.insert(1, vec![0; 2]);
+    assert_eq!(cache.stats().bytes, 2);
+    cache.insert(2, vec![0; 11]);
+    assert!(cache.get(&2).is_none());
+    assert_eq!(cache.remove(&1), Some(vec![0; 2]));
+    assert_eq!(cache.stats().bytes, 0);
+    assert_eq!(cache.stats().evictions, 0);
+}
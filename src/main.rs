@@ -1,13 +1,32 @@
 extern crate gtk;
 extern crate gdk;
 extern crate gdk_pixbuf;
+extern crate glib;
 extern crate twitch_chat;
 extern crate twitch_api;
+extern crate hyper;
+extern crate hyper_rustls;
+extern crate serde_json;
 
 pub mod ui;
 pub mod twitchrs;
 pub mod twitch_message;
 pub mod twitch_image_loader;
+pub mod credentials;
+pub mod send_queue;
+pub mod chat_error;
+pub mod session;
+pub mod credential_store;
+pub mod passphrase;
+pub mod token_validator;
+pub mod emote_match;
+pub mod disk_cache;
+pub mod lru_cache;
+pub mod in_flight;
+pub mod image_source;
+pub mod twitch_source;
+#[cfg(test)]
+pub mod fixture_source;
 
 fn main() {
     let mut trs = twitchrs::TwitchRS::new();
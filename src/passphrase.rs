@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/* First line of a file sealed with a passphrase. Saved account lines always
+ * have a "=", so this can't be mistaken for one.
+ */
+const HEADER: &'static str = "#twitchrs encrypted";
+/* PBKDF2 rounds for new files, kept in the file so it can change later */
+const ITERATIONS: u32 = 100000;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+pub fn is_sealed(contents: &str) -> bool
+{
+    contents.starts_with(HEADER)
+}
+
+/* Keys derived from a passphrase, kept so saving again doesn't take another
+ * PBKDF2 run. The key is derived with PBKDF2-HMAC-SHA256, the text is
+ * encrypted with ChaCha20 and everything before the last line is
+ * authenticated with HMAC-SHA256, which is how a wrong passphrase shows.
+ */
+pub struct SealingKey
+{
+    iterations: u32,
+    salt: Vec<u8>,
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl SealingKey
+{
+    /* With a new random salt */
+    pub fn new(passphrase: &str) -> io::Result<SealingKey>
+    {
+        let salt = try!(random_bytes(SALT_LEN));
+        Ok(SealingKey::derive(passphrase, salt, ITERATIONS))
+    }
+
+    /* Few rounds, so tests don't wait on PBKDF2 */
+    #[cfg(test)]
+    pub fn for_tests(passphrase: &str) -> SealingKey
+    {
+        SealingKey::derive(passphrase, b"0123456789abcdef".to_vec(), 10)
+    }
+
+    fn derive(passphrase: &str, salt: Vec<u8>, iterations: u32) -> SealingKey
+    {
+        let mut keys = [0; 64];
+        pbkdf2_sha256(passphrase.as_bytes(), &salt, iterations, &mut keys);
+        let mut cipher_key = [0; 32];
+        let mut mac_key = [0; 32];
+        cipher_key.copy_from_slice(&keys[..32]);
+        mac_key.copy_from_slice(&keys[32..]);
+        SealingKey
+        {
+            iterations: iterations,
+            salt: salt,
+            cipher_key: cipher_key,
+            mac_key: mac_key,
+        }
+    }
+
+    pub fn seal(&self, plain: &[u8]) -> io::Result<String>
+    {
+        let nonce = try!(random_bytes(NONCE_LEN));
+        Ok(self.seal_with_nonce(plain, &nonce))
+    }
+
+    fn seal_with_nonce(&self, plain: &[u8], nonce: &[u8]) -> String
+    {
+        let mut data = plain.to_vec();
+        chacha20(&self.cipher_key, nonce, 0, &mut data);
+
+        let mut sealed = format!("{}\niterations={}\nsalt={}\nnonce={}\ndata={}\n",
+                                 HEADER,
+                                 self.iterations,
+                                 to_hex(&self.salt),
+                                 to_hex(nonce),
+                                 to_hex(&data));
+        let mac = HmacSha256::new(&self.mac_key).mac(sealed.as_bytes());
+        sealed.push_str(&format!("mac={}\n", to_hex(&mac)));
+        sealed
+    }
+}
+
+/* The contents and the key to seal them again with. None when the
+ * passphrase is wrong or the file was changed.
+ */
+pub fn open(passphrase: &str, sealed: &str) -> Option<(SealingKey, Vec<u8>)>
+{
+    let mac_start = match sealed.rfind("\nmac=")
+    {
+        Some(newline) => newline + 1,
+        None => return None,
+    };
+    let (authenticated, mac_line) = sealed.split_at(mac_start);
+
+    let fields: HashMap<&str, &str> = authenticated.lines()
+                                                   .filter_map(|line| {
+                                                       let mut parts = line.splitn(2, '=');
+                                                       match (parts.next(), parts.next())
+                                                       {
+                                                           (Some(name), Some(value)) => Some((name, value)),
+                                                           _ => None,
+                                                       }
+                                                   })
+                                                   .collect();
+    let iterations = fields.get("iterations").and_then(|iterations| u32::from_str(iterations).ok());
+    let salt = fields.get("salt").and_then(|salt| from_hex(salt));
+    let nonce = fields.get("nonce").and_then(|nonce| from_hex(nonce));
+    let data = fields.get("data").and_then(|data| from_hex(data));
+    let mac = from_hex(mac_line["mac=".len()..].trim_right());
+    let (iterations, salt, nonce, mut data, mac) = match (iterations, salt, nonce, data, mac)
+    {
+        (Some(iterations), Some(salt), Some(nonce), Some(data), Some(mac)) =>
+            (iterations, salt, nonce, data, mac),
+        _ => return None,
+    };
+    if !is_sealed(authenticated) || iterations == 0 || nonce.len() != NONCE_LEN
+    {
+        return None;
+    }
+
+    let key = SealingKey::derive(passphrase, salt, iterations);
+    if !same_bytes(&HmacSha256::new(&key.mac_key).mac(authenticated.as_bytes()), &mac)
+    {
+        return None;
+    }
+    chacha20(&key.cipher_key, &nonce, 0, &mut data);
+    Some((key, data))
+}
+
+fn random_bytes(len: usize) -> io::Result<Vec<u8>>
+{
+    let mut bytes = vec![0; len];
+    try!(try!(File::open("/dev/urandom")).read_exact(&mut bytes));
+    Ok(bytes)
+}
+
+/* Takes as long whatever the first difference is */
+fn same_bytes(a: &[u8], b: &[u8]) -> bool
+{
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter()
+         .map(|byte| format!("{:02x}", byte))
+         .collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>>
+{
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_digit(16))
+    {
+        return None;
+    }
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok())
+                      .collect()
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+#[derive(Clone)]
+struct Sha256
+{
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    length: u64,
+}
+
+impl Sha256
+{
+    fn new() -> Sha256
+    {
+        Sha256
+        {
+            state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+            block: [0; 64],
+            filled: 0,
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8])
+    {
+        self.length += data.len() as u64;
+        let mut data = data;
+        while !data.is_empty()
+        {
+            let take = if data.len() < 64 - self.filled { data.len() } else { 64 - self.filled };
+            self.block[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == 64
+            {
+                sha256_compress(&mut self.state, &self.block);
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32]
+    {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.filled != 56
+        {
+            self.update(&[0]);
+        }
+        let length: Vec<u8> = (0..8).rev()
+                                    .map(|i| (bits >> (i * 8)) as u8)
+                                    .collect();
+        self.update(&length);
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter())
+        {
+            bytes.copy_from_slice(&[(word >> 24) as u8, (word >> 16) as u8, (word >> 8) as u8, *word as u8]);
+        }
+        digest
+    }
+}
+
+fn sha256_compress(state: &mut [u32; 8], block: &[u8; 64])
+{
+    let mut w = [0u32; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks(4))
+    {
+        *word = (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32;
+    }
+    for i in 16..64
+    {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+
+    let mut h = *state;
+    for (k, w) in SHA256_K.iter().zip(w.iter())
+    {
+        let s1 = h[4].rotate_right(6) ^ h[4].rotate_right(11) ^ h[4].rotate_right(25);
+        let ch = (h[4] & h[5]) ^ (!h[4] & h[6]);
+        let t1 = h[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(*k).wrapping_add(*w);
+        let s0 = h[0].rotate_right(2) ^ h[0].rotate_right(13) ^ h[0].rotate_right(22);
+        let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+        let t2 = s0.wrapping_add(maj);
+        h = [t1.wrapping_add(t2), h[0], h[1], h[2], h[3].wrapping_add(t1), h[4], h[5], h[6]];
+    }
+    for (state, h) in state.iter_mut().zip(h.iter())
+    {
+        *state = state.wrapping_add(*h);
+    }
+}
+
+/* Keyed once, PBKDF2 reuses it for every round */
+struct HmacSha256
+{
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256
+{
+    fn new(key: &[u8]) -> HmacSha256
+    {
+        let mut block = [0; 64];
+        if key.len() > 64
+        {
+            let mut hash = Sha256::new();
+            hash.update(key);
+            block[..32].copy_from_slice(&hash.finish());
+        }
+        else
+        {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.iter().map(|byte| byte ^ 0x36).collect::<Vec<u8>>());
+        let mut outer = Sha256::new();
+        outer.update(&block.iter().map(|byte| byte ^ 0x5c).collect::<Vec<u8>>());
+        HmacSha256
+        {
+            inner: inner,
+            outer: outer,
+        }
+    }
+
+    fn mac(&self, data: &[u8]) -> [u8; 32]
+    {
+        let mut inner = self.inner.clone();
+        inner.update(data);
+        let mut outer = self.outer.clone();
+        outer.update(&inner.finish());
+        outer.finish()
+    }
+}
+
+fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, out: &mut [u8])
+{
+    let prf = HmacSha256::new(passphrase);
+    for (index, chunk) in out.chunks_mut(32).enumerate()
+    {
+        let block = index as u32 + 1;
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(&[(block >> 24) as u8, (block >> 16) as u8, (block >> 8) as u8, block as u8]);
+
+        let mut u = prf.mac(&salted);
+        let mut t = u;
+        for _ in 1..iterations
+        {
+            u = prf.mac(&u);
+            for (t, u) in t.iter_mut().zip(u.iter())
+            {
+                *t ^= *u;
+            }
+        }
+        let len = chunk.len();
+        chunk.copy_from_slice(&t[..len]);
+    }
+}
+
+/* Encrypts or decrypts in place, the nonce is 12 bytes */
+fn chacha20(key: &[u8; 32], nonce: &[u8], counter: u32, data: &mut [u8])
+{
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (word, bytes) in state[4..12].iter_mut().zip(key.chunks(4))
+    {
+        *word = le_word(bytes);
+    }
+    for (word, bytes) in state[13..].iter_mut().zip(nonce.chunks(4))
+    {
+        *word = le_word(bytes);
+    }
+
+    for (index, chunk) in data.chunks_mut(64).enumerate()
+    {
+        state[12] = counter.wrapping_add(index as u32);
+        let mut x = state;
+        for _ in 0..10
+        {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        let mut keystream = [0; 64];
+        for ((bytes, x), input) in keystream.chunks_mut(4).zip(x.iter()).zip(state.iter())
+        {
+            let word = x.wrapping_add(*input);
+            bytes.copy_from_slice(&[word as u8, (word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8]);
+        }
+        for (byte, key) in chunk.iter_mut().zip(keystream.iter())
+        {
+            *byte ^= *key;
+        }
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize)
+{
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+fn le_word(bytes: &[u8]) -> u32
+{
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+#[cfg(test)]
+fn sha256(data: &[u8]) -> [u8; 32]
+{
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+#[test]
+fn test_sha256()
+{
+    assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(to_hex(&sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+}
+
+#[test]
+fn test_hmac_sha256()
+{
+    /* RFC 4231, test case 2 */
+    assert_eq!(to_hex(&HmacSha256::new(b"Jefe").mac(b"what do ya want for nothing?")),
+               "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(to_hex(&HmacSha256::new(&[b'k'; 100]).mac(b"long key")),
+               "5903eedefe0bba3f15b658715e41d71f1848739bddf19e497e968df5723dcd56");
+}
+
+#[test]
+fn test_pbkdf2_sha256()
+{
+    /* RFC 7914, section 11 */
+    let mut out = [0; 64];
+    pbkdf2_sha256(b"passwd", b"salt", 1, &mut out);
+    assert_eq!(to_hex(&out), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+                              49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783");
+}
+
+#[test]
+fn test_chacha20()
+{
+    /* RFC 7539, section 2.4.2 */
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate()
+    {
+        *byte = i as u8;
+    }
+    let nonce = from_hex("000000000000004a00000000").unwrap();
+    let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+    chacha20(&key, &nonce, 1, &mut data);
+    assert_eq!(to_hex(&data), "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+                               f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+                               07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+                               5af90bbf74a35be6b40b8eedf2785e42874d");
+}
+
+#[test]
+fn test_seal_and_open()
+{
+    let key = SealingKey::for_tests("hunter2");
+    let sealed = key.seal_with_nonce(b"onvar=oauth:abc\n", b"0123456789ab");
+    assert!(is_sealed(&sealed));
+    assert!(!sealed.contains("oauth"));
+    let (key, plain) = open("hunter2", &sealed).unwrap();
+    assert_eq!(plain, b"onvar=oauth:abc\n".to_vec());
+    assert_eq!(open("hunter2", &key.seal(b"bot=oauth:def\n").unwrap()).unwrap().1, b"bot=oauth:def\n".to_vec());
+    assert!(open("hunter3", &sealed).is_none());
+    assert!(open("hunter2", &sealed.replace("iterations=10", "iterations=11")).is_none());
+    assert!(open("hunter2", "onvar=oauth:abc\n").is_none());
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* Twitch locks accounts out for 30 minutes when these are exceeded */
+const MESSAGE_WINDOW_SECS: u64 = 30;
+const MESSAGE_LIMIT: usize = 20;
+const PRIVILEGED_MESSAGE_LIMIT: usize = 100;
+const JOIN_WINDOW_SECS: u64 = 10;
+const JOIN_LIMIT: usize = 20;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Outgoing
+{
+    Message(String, String),
+    /* Channel, id of the message replied to, text */
+    Reply(String, String, String),
+    Join(String),
+}
+
+impl Outgoing
+{
+    pub fn channel(&self) -> &str
+    {
+        match *self
+        {
+            Outgoing::Message(ref channel, _) => channel,
+            Outgoing::Reply(ref channel, _, _) => channel,
+            Outgoing::Join(ref channel) => channel,
+        }
+    }
+}
+
+pub struct SendQueue
+{
+    /* Messages and replies, which share a rate limit */
+    messages: VecDeque<Outgoing>,
+    joins: VecDeque<String>,
+    sent_messages: VecDeque<Instant>,
+    sent_joins: VecDeque<Instant>,
+}
+
+impl SendQueue
+{
+    pub fn new() -> SendQueue
+    {
+        SendQueue
+        {
+            messages: VecDeque::new(),
+            joins: VecDeque::new(),
+            sent_messages: VecDeque::new(),
+            sent_joins: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, outgoing: Outgoing)
+    {
+        match outgoing
+        {
+            Outgoing::Join(channel) => self.joins.push_back(channel),
+            message => self.messages.push_back(message),
+        }
+    }
+
+    /* Takes the next message or join that can go out right now without
+     * going over the limits. is_privileged tells whether we are a
+     * broadcaster, moderator or VIP in a channel, which raises the limit
+     * for messages sent there.
+     */
+    pub fn pop_ready<F>(&mut self, is_privileged: F) -> Option<Outgoing>
+        where F: Fn(&str) -> bool
+    {
+        let now = Instant::now();
+        forget_before(&mut self.sent_messages, now, Duration::from_secs(MESSAGE_WINDOW_SECS));
+        forget_before(&mut self.sent_joins, now, Duration::from_secs(JOIN_WINDOW_SECS));
+
+        if !self.joins.is_empty() && self.sent_joins.len() < JOIN_LIMIT
+        {
+            self.sent_joins.push_back(now);
+            return self.joins.pop_front().map(Outgoing::Join);
+        }
+
+        let limit = match self.messages.front()
+        {
+            Some(message) if is_privileged(message.channel()) => PRIVILEGED_MESSAGE_LIMIT,
+            Some(_) => MESSAGE_LIMIT,
+            None => return None,
+        };
+        if self.sent_messages.len() < limit
+        {
+            self.sent_messages.push_back(now);
+            return self.messages.pop_front();
+        }
+
+        None
+    }
+
+    /* Empties the queue, handing back the messages that were never sent.
+     * Joins are dropped since reconnecting joins every open channel anyway.
+     */
+    pub fn drain_messages(&mut self) -> Vec<Outgoing>
+    {
+        self.joins.clear();
+        self.messages.drain(..).collect()
+    }
+
+    /* Drops anything still waiting for a channel we are leaving */
+    pub fn forget_channel(&mut self, channel: &str)
+    {
+        self.messages.retain(|message| message.channel() != channel);
+        self.joins.retain(|c| c != channel);
+    }
+
+    /* Messages to a channel still waiting on the rate limit */
+    pub fn queued_for(&self, channel: &str) -> usize
+    {
+        self.messages.iter()
+                     .filter(|message| message.channel() == channel)
+                     .count()
+    }
+}
+
+fn forget_before(sent: &mut VecDeque<Instant>, now: Instant, window: Duration)
+{
+    while let Some(&time) = sent.front()
+    {
+        if now.duration_since(time) < window
+        {
+            break;
+        }
+        sent.pop_front();
+    }
+}
+
+#[test]
+fn test_send_queue_limits_messages()
+{
+    let mut queue = SendQueue::new();
+    for i in 0..MESSAGE_LIMIT + 1
+    {
+        queue.push(Outgoing::Message("#onvar".into(), format!("{}", i)));
+    }
+    for _ in 0..MESSAGE_LIMIT
+    {
+        assert!(queue.pop_ready(|_| false).is_some());
+    }
+    assert_eq!(queue.pop_ready(|_| false), None);
+    assert_eq!(queue.queued_for("#onvar"), 1);
+    assert_eq!(queue.pop_ready(|_| true), Some(Outgoing::Message("#onvar".into(), format!("{}", MESSAGE_LIMIT))));
+}
+
+#[test]
+fn test_send_queue_joins_do_not_wait_on_messages()
+{
+    let mut queue = SendQueue::new();
+    for _ in 0..MESSAGE_LIMIT
+    {
+        queue.push(Outgoing::Message("#onvar".into(), "hi".into()));
+        queue.pop_ready(|_| false);
+    }
+    queue.push(Outgoing::Message("#onvar".into(), "hi".into()));
+    queue.push(Outgoing::Join("#other".into()));
+    assert_eq!(queue.pop_ready(|_| false), Some(Outgoing::Join("#other".into())));
+    assert_eq!(queue.pop_ready(|_| false), None);
+}
+
+#[test]
+fn test_send_queue_replies_share_the_message_limit()
+{
+    let mut queue = SendQueue::new();
+    for _ in 0..MESSAGE_LIMIT - 1
+    {
+        queue.push(Outgoing::Message("#onvar".into(), "hi".into()));
+    }
+    queue.push(Outgoing::Reply("#onvar".into(), "abc".into(), "hi".into()));
+    queue.push(Outgoing::Reply("#onvar".into(), "def".into(), "hi".into()));
+    queue.push(Outgoing::Reply("#other".into(), "ghi".into(), "hi".into()));
+    for _ in 0..MESSAGE_LIMIT - 1
+    {
+        queue.pop_ready(|_| false);
+    }
+    assert_eq!(queue.pop_ready(|_| false), Some(Outgoing::Reply("#onvar".into(), "abc".into(), "hi".into())));
+    assert_eq!(queue.pop_ready(|_| false), None);
+    queue.forget_channel("#onvar");
+    assert_eq!(queue.queued_for("#onvar"), 0);
+    assert_eq!(queue.drain_messages(), vec![Outgoing::Reply("#other".into(), "ghi".into(), "hi".into())]);
+}
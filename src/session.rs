@@ -0,0 +1,135 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+const SESSION_FILE: &'static str = "session";
+const ANONYMOUS: &'static str = "anonymous";
+
+/* What is restored on the next start */
+#[derive(Clone,Debug,Default)]
+pub struct Session
+{
+    /* Every account that was logged in, None for an anonymous login.
+     * Only the username is kept here, its token is in the credential store.
+     */
+    pub logins: Vec<Option<String>>,
+    /* Joined channels in tab order */
+    pub channels: Vec<String>,
+}
+
+impl Session
+{
+    /* A missing or unreadable file just means a fresh start */
+    pub fn load() -> Session
+    {
+        let mut contents = String::new();
+        match config_dir().map(|dir| dir.join(SESSION_FILE))
+                          .and_then(|path| File::open(path).ok())
+        {
+            Some(mut file) =>
+            {
+                if file.read_to_string(&mut contents).is_err()
+                {
+                    return Session::default();
+                }
+                Session::parse(&contents)
+            },
+            None => Session::default(),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()>
+    {
+        let dir = try!(config_dir().ok_or(io::Error::new(io::ErrorKind::NotFound,
+                                                         "no config directory")));
+        try!(fs::create_dir_all(&dir));
+        let mut file = try!(open_private(dir.join(SESSION_FILE)));
+        file.write_all(self.serialize().as_bytes())
+    }
+
+    /* One "key=value" per line, unknown keys are skipped */
+    pub fn parse(contents: &str) -> Session
+    {
+        let mut session = Session::default();
+        for line in contents.lines()
+        {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next())
+            {
+                (Some("login"), Some(ANONYMOUS)) => session.logins.push(None),
+                (Some("login"), Some(value)) => session.logins.push(Some(String::from(value))),
+                (Some("channel"), Some(value)) => session.channels.push(String::from(value)),
+                _ => (),
+            }
+        }
+        session
+    }
+
+    pub fn serialize(&self) -> String
+    {
+        let mut contents = String::new();
+        for login in &self.logins
+        {
+            let username = login.as_ref()
+                                .map(String::as_str)
+                                .unwrap_or(ANONYMOUS);
+            contents.push_str(&format!("login={}\n", username));
+        }
+        for channel in &self.channels
+        {
+            contents.push_str(&format!("channel={}\n", channel));
+        }
+        contents
+    }
+}
+
+/* $XDG_CONFIG_HOME/twitchrs, falling back to ~/.config/twitchrs */
+pub fn config_dir() -> Option<PathBuf>
+{
+    let base = match env::var_os("XDG_CONFIG_HOME")
+    {
+        Some(ref dir) if PathBuf::from(dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    base.map(|dir| dir.join("twitchrs"))
+}
+
+/* For files that may hold OAuth tokens */
+#[cfg(unix)]
+pub fn open_private(path: PathBuf) -> io::Result<File>
+{
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = try!(OpenOptions::new().write(true)
+                                      .create(true)
+                                      .truncate(true)
+                                      .mode(0o600)
+                                      .open(path));
+    /* mode() only applies when the file is created */
+    try!(file.set_permissions(fs::Permissions::from_mode(0o600)));
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub fn open_private(path: PathBuf) -> io::Result<File>
+{
+    OpenOptions::new().write(true)
+                      .create(true)
+                      .truncate(true)
+                      .open(path)
+}
+
+#[test]
+fn test_session_round_trip()
+{
+    let session = Session
+    {
+        logins: vec![Some("onvar".into()), None, Some("onvarbot".into())],
+        channels: vec!["#onvar".into(), "#b".into(), "#a".into()],
+    };
+    let restored = Session::parse(&session.serialize());
+    assert_eq!(restored.logins, session.logins);
+    assert_eq!(restored.channels, session.channels);
+    assert!(Session::parse("").logins.is_empty());
+}
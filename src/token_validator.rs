@@ -0,0 +1,157 @@
+use hyper;
+use hyper::header::Authorization;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper_rustls::TlsClient;
+use serde_json::{self, Value};
+
+use std::env;
+use std::fmt;
+use std::io::{self, Read};
+
+const VALIDATE_URL: &'static str = "https://id.twitch.tv/oauth2/validate";
+/* Points validation somewhere else, like a local test server */
+const VALIDATE_URL_VAR: &'static str = "TWITCHRS_VALIDATE_URL";
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct TokenInfo
+{
+    pub login: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ValidationError
+{
+    /* Twitch does not accept the token */
+    Rejected,
+    Http(hyper::Error),
+    Io(io::Error),
+    BadResponse,
+}
+
+impl fmt::Display for ValidationError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            ValidationError::Rejected => write!(f, "invalid or expired OAuth token"),
+            ValidationError::Http(ref e) => write!(f, "could not reach the validation server: {}", e),
+            ValidationError::Io(ref e) => write!(f, "could not read the validation response: {}", e),
+            ValidationError::BadResponse => write!(f, "unexpected response from the validation server"),
+        }
+    }
+}
+
+pub struct TokenValidator
+{
+    url: String,
+}
+
+impl TokenValidator
+{
+    pub fn new(url: String) -> TokenValidator
+    {
+        TokenValidator
+        {
+            url: url,
+        }
+    }
+
+    /* Twitch's endpoint unless overridden from the environment */
+    pub fn from_env() -> TokenValidator
+    {
+        TokenValidator::new(env::var(VALIDATE_URL_VAR).unwrap_or(String::from(VALIDATE_URL)))
+    }
+
+    pub fn validate(&self, oauth: &str) -> Result<TokenInfo, ValidationError>
+    {
+        /* The chat login wants "oauth:" in front, the API does not */
+        let token = oauth.trim_left_matches("oauth:");
+        let client = hyper::Client::with_connector(HttpsConnector::new(TlsClient::new()));
+        let mut response = try!(client.get(&self.url)
+                                      .header(Authorization(format!("OAuth {}", token)))
+                                      .send()
+                                      .map_err(ValidationError::Http));
+        match response.status
+        {
+            StatusCode::Ok => (),
+            StatusCode::Unauthorized => return Err(ValidationError::Rejected),
+            _ => return Err(ValidationError::BadResponse),
+        }
+
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body).map_err(ValidationError::Io));
+        parse_token_info(&body).ok_or(ValidationError::BadResponse)
+    }
+}
+
+fn parse_token_info(body: &str) -> Option<TokenInfo>
+{
+    let value: Value = match serde_json::from_str(body)
+    {
+        Ok(value) => value,
+        Err(_) => return None,
+    };
+    let object = match value.as_object()
+    {
+        Some(object) => object,
+        None => return None,
+    };
+    let login = match object.get("login").and_then(Value::as_str)
+    {
+        Some(login) => String::from(login),
+        None => return None,
+    };
+    let scopes = object.get("scopes")
+                       .and_then(Value::as_array)
+                       .map(|scopes| scopes.iter()
+                                           .filter_map(Value::as_str)
+                                           .map(String::from)
+                                           .collect())
+                       .unwrap_or(Vec::new());
+    Some(TokenInfo
+    {
+        login: login,
+        scopes: scopes,
+    })
+}
+
+#[cfg(test)]
+fn serve_once(status: &'static str, body: &'static str) -> String
+{
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 4096];
+        let _ = stream.read(&mut request);
+        write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, body.len(), body).unwrap();
+    });
+    format!("http://{}/oauth2/validate", addr)
+}
+
+#[test]
+fn test_validate_accepted_token()
+{
+    let url = serve_once("200 OK", r#"{"client_id":"x","login":"onvar","scopes":["chat:read","chat:edit"],"user_id":"1"}"#);
+    let info = TokenValidator::new(url).validate("oauth:abc").unwrap();
+    assert_eq!(info, TokenInfo { login: "onvar".into(), scopes: vec!["chat:read".into(), "chat:edit".into()] });
+}
+
+#[test]
+fn test_validate_rejected_token()
+{
+    let url = serve_once("401 Unauthorized", r#"{"status":401,"message":"invalid access token"}"#);
+    match TokenValidator::new(url).validate("oauth:abc")
+    {
+        Err(ValidationError::Rejected) => (),
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
@@ -1,354 +1,499 @@
-use twitch_message::{TwitchBadge, TwitchEmote};
+use twitch_message::{TwitchBadge, TwitchCheer, TwitchEmote};
+use image_source::ImageSource;
+use twitch_source::TwitchSource;
+use in_flight::InFlight;
+use lru_cache::{CacheStats, LruCache};
 
-use twitch_api::TwitchApi;
-use twitch_api::model::emoticon::Emoticon;
-
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::{self, Sender, Receiver};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::io::Read;
+use std::time::Duration;
+use std::fmt;
+
+/* Bytes of downloaded images kept in memory */
+const URL_CACHE_BYTES: usize = 16 * 1024 * 1024;
+const EMOTE_CACHE_BYTES: usize = 32 * 1024 * 1024;
+const CHEERMOTE_CACHE_BYTES: usize = 8 * 1024 * 1024;
+/* Downloads that can run at once */
+const WORKERS: usize = 4;
+/* Network failures are tried again this many times, waiting twice as long
+ * after each one.
+ */
+const RETRIES: u32 = 3;
+const RETRY_DELAY_MS: u64 = 500;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum ImageError
+{
+    /* There is no such image, asking again will not change that */
+    NotFound,
+    Network(String),
+}
+
+impl fmt::Display for ImageError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            ImageError::NotFound => write!(f, "not found"),
+            ImageError::Network(ref reason) => write!(f, "network error: {}", reason),
+        }
+    }
+}
 
+pub type ImageResult<T> = Result<T, ImageError>;
+
+/* Names an image whatever URL it ends up coming from */
+#[derive(Clone,Debug,Eq,PartialEq,Hash)]
+pub enum ImageKey
+{
+    Badge(TwitchBadge),
+    /* Channel login and badge */
+    SubBadge(String, TwitchBadge),
+    Emote(u64),
+    /* Lowercase prefix and tier */
+    Cheermote(String, u64),
+}
+
+/* Badge lists, loaded once however many badges are waiting on them */
+#[derive(Clone,Debug,Eq,PartialEq,Hash)]
+enum ListKey
+{
+    GlobalBadges,
+    /* Channel login */
+    SubBadges(String),
+}
+
+/* Only sent for the first request of a key, the others wait in InFlight */
 #[derive(Clone,Debug)]
 enum ImageMessage
 {
-    GetBadge(TwitchBadge, Sender<Vec<u8>>),
-    GetSubBadge((String, TwitchBadge), Sender<Vec<u8>>),
-    GetEmote(u64, Sender<Vec<u8>>),
-    GetEmoteSet(u64, Sender<Vec<TwitchEmote>>),
+    GetImage(ImageKey),
+    GetEmoteSet(u64),
+}
+
+/* What the GTK thread and the workers share. Locks are only held to look
+ * something up or store it, never while downloading.
+ */
+#[derive(Debug)]
+struct Shared
+{
+    source: Arc<ImageSource>,
+    /* Before the first retry, doubled for each one after */
+    retry_delay: Duration,
+    badge_url_cache: Mutex<HashMap<TwitchBadge, String>>,
+    sub_badge_url_cache: Mutex<HashMap<(String, TwitchBadge), String>>,
+    url_cache: Mutex<LruCache<String, Vec<u8>>>,
+    emote_cache: Mutex<LruCache<u64, Vec<u8>>>,
+    emote_set_cache: Mutex<HashMap<u64, Vec<TwitchEmote>>>,
+    /* By prefix and tier */
+    cheermote_cache: Mutex<LruCache<(String, u64), Vec<u8>>>,
+    /* Images that came back NotFound are not asked for again */
+    missing: Mutex<HashSet<ImageKey>>,
+    images_in_flight: InFlight<ImageKey, ImageResult<Vec<u8>>>,
+    emote_sets_in_flight: InFlight<u64, ImageResult<Vec<TwitchEmote>>>,
+    lists_in_flight: InFlight<ListKey, ImageResult<()>>,
 }
 
 #[derive(Clone,Debug)]
 pub struct TwitchImageLoader
 {
-    badge_url_cache: Arc<Mutex<HashMap<TwitchBadge, String>>>,
-    sub_badge_url_cache: Arc<Mutex<HashMap<(String, TwitchBadge), String>>>,
-    url_cache: Arc<Mutex<HashMap<String, Vec<u8>>>>,
-    emote_cache: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
-    emote_set_cache: Arc<Mutex<HashMap<u64, Vec<TwitchEmote>>>>,
+    shared: Arc<Shared>,
     sender: Sender<ImageMessage>,
 }
 
 impl TwitchImageLoader
 {
+    /* Loads from Twitch */
     pub fn new(client_id: &str) -> TwitchImageLoader
     {
-        let tapi = TwitchApi::new(client_id);
+        TwitchImageLoader::with_source(Arc::new(TwitchSource::new(client_id)))
+    }
+
+    pub fn with_source(source: Arc<ImageSource>) -> TwitchImageLoader
+    {
+        TwitchImageLoader::start(source, Duration::from_millis(RETRY_DELAY_MS))
+    }
+
+    fn start(source: Arc<ImageSource>, retry_delay: Duration) -> TwitchImageLoader
+    {
         let (tx, rx) = mpsc::channel();
-        let badge_url_cache = Arc::new(Mutex::new(HashMap::<TwitchBadge, String>::new()));
-        let sub_badge_url_cache = Arc::new(Mutex::new(HashMap::<(String, TwitchBadge), String>::new()));
-        let url_cache = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
-        let emote_cache = Arc::new(Mutex::new(HashMap::<u64, Vec<u8>>::new()));
-        let emote_set_cache = Arc::new(Mutex::new(HashMap::<u64, Vec<TwitchEmote>>::new()));
-
-        let badge_url_cache_clone = badge_url_cache.clone();
-        let sub_badge_url_cache_clone = sub_badge_url_cache.clone();
-        let url_cache_clone = url_cache.clone();
-        let emote_cache_clone = emote_cache.clone();
-        let emote_set_cache_clone = emote_set_cache.clone();
-        thread::spawn(move || {
-            loop
-            {
-                match rx.recv()
+        let shared = Arc::new(Shared
+        {
+            source: source,
+            retry_delay: retry_delay,
+            badge_url_cache: Mutex::new(HashMap::new()),
+            sub_badge_url_cache: Mutex::new(HashMap::new()),
+            url_cache: Mutex::new(LruCache::new(URL_CACHE_BYTES)),
+            emote_cache: Mutex::new(LruCache::new(EMOTE_CACHE_BYTES)),
+            emote_set_cache: Mutex::new(HashMap::new()),
+            cheermote_cache: Mutex::new(LruCache::new(CHEERMOTE_CACHE_BYTES)),
+            missing: Mutex::new(HashSet::new()),
+            images_in_flight: InFlight::new(),
+            emote_sets_in_flight: InFlight::new(),
+            lists_in_flight: InFlight::new(),
+        });
+
+        /* Workers take turns waiting on the one queue */
+        let receiver = Arc::new(Mutex::new(rx));
+        for _ in 0..WORKERS
+        {
+            let shared_clone = shared.clone();
+            let receiver_clone = receiver.clone();
+            thread::spawn(move || {
+                loop
                 {
-                    Ok(msg) =>
+                    let message = match receiver_clone.lock().unwrap().recv()
                     {
-                        match msg
+                        Ok(message) => message,
+                        Err(_) => break,
+                    };
+                    match message
+                    {
+                        ImageMessage::GetImage(key) =>
                         {
-                            ImageMessage::GetBadge(badge, tx) =>
-                            {
-                                /* If badge in badge->url cache
-                                 *     if url in url->bin cache
-                                 *         callback(badge, bin)
-                                 *     else
-                                 *         get url
-                                 *         add to url->bin cache
-                                 *         callback(badge, bin)
-                                 * else
-                                 *     get badges
-                                 *     add badges to badge->url cache
-                                 *     get url
-                                 *     add url to url->bin cache
-                                 *     callback(badge, bin)
-                                 */
-                                let mut badge_url_cache = badge_url_cache_clone.lock().unwrap();
-                                let mut url_cache = url_cache_clone.lock().unwrap();
-
-                                if let Some(url) = badge_url_cache.get(&badge)
-                                {
-                                    if let Some(bin) = url_cache.get(url)
-                                    {
-                                        tx.send(bin.clone()).unwrap();
-                                        continue;
-                                    }
-                                    let response_opt = tapi.make_raw_request(url);
-                                    if let Ok(mut response) = response_opt
-                                    {
-                                        let mut buffer = Vec::new();
-                                        response.read_to_end(&mut buffer).unwrap();
-                                        tx.send(buffer.clone()).unwrap();
-                                        url_cache.insert(url.clone(), buffer);
-                                        continue;
-                                    }
-                                }
-                                println!("GETTING BADGES");
-                                let badge_sets = tapi.get_global_badges();
-                                if let Ok(badge_sets) = badge_sets
-                                {
-                                    for set in badge_sets.badge_sets.keys()
-                                    {
-                                        let badge_set = badge_sets.badge_sets.get(set).unwrap();
-                                        for version in badge_set.versions.keys()
-                                        {
-                                            let badge = TwitchBadge {
-                                                set: set.clone(),
-                                                version: version.clone(),
-                                            };
-                                            let url = badge_set.versions
-                                                               .get(version)
-                                                               .unwrap()
-                                                               .image_url_1x
-                                                               .clone();
-                                            println!("BADGE {:?}, URL {}", badge, url);
-                                            badge_url_cache.insert(badge, url);
-                                        }
-                                    }
-                                }
-                                if let Some(url) = badge_url_cache.get(&badge)
-                                {
-                                    let response_opt = tapi.make_raw_request(url);
-                                    if let Ok(mut response) = response_opt
-                                    {
-                                        let mut buffer = Vec::new();
-                                        println!("GOT BADGE DATA FOR URL {:?}", url);
-                                        response.read_to_end(&mut buffer).unwrap();
-                                        tx.send(buffer.clone()).unwrap();
-                                        url_cache.insert(url.clone(), buffer);
-                                    }
-                                }
-                            },
-                            ImageMessage::GetSubBadge(badge, tx) =>
+                            let image = shared_clone.fetch_image(&key);
+                            if let Err(ImageError::NotFound) = image
                             {
-                                /* If badge in badge->url cache
-                                 *     if url in url->bin cache
-                                 *         callback(badge, bin)
-                                 *     else
-                                 *         get url
-                                 *         add to url->bin cache
-                                 *         callback(badge, bin)
-                                 * else
-                                 *     get badges
-                                 *     add badges to badge->url cache
-                                 *     get url
-                                 *     add url to url->bin cache
-                                 *     callback(badge, bin)
-                                 */
-                                let mut sub_badge_url_cache = sub_badge_url_cache_clone.lock().unwrap();
-                                let mut url_cache = url_cache_clone.lock().unwrap();
-
-                                if let Some(url) = sub_badge_url_cache.get(&badge)
-                                {
-                                    if let Some(bin) = url_cache.get(url)
-                                    {
-                                        tx.send(bin.clone()).unwrap();
-                                        continue;
-                                    }
-                                    let response_opt = tapi.make_raw_request(url);
-                                    if let Ok(mut response) = response_opt
-                                    {
-                                        let mut buffer = Vec::new();
-                                        response.read_to_end(&mut buffer).unwrap();
-                                        tx.send(buffer.clone()).unwrap();
-                                        url_cache.insert(url.clone(), buffer);
-                                        continue;
-                                    }
-                                }
-                                println!("GETTING BADGES");
-                                let user = tapi.get_user(&badge.0).unwrap().users.get(0).unwrap().id;
-                                let badge_sets = tapi.get_subscriber_badges(user);
-                                if let Ok(badge_sets) = badge_sets
-                                {
-                                    if let Some(subscriber_set) = badge_sets.badge_sets.get("subscriber")
-                                    {
-                                        for version in subscriber_set.versions.keys()
-                                        {
-                                            let sub_badge = TwitchBadge {
-                                                set: "subscriber".into(),
-                                                version: version.clone(),
-                                            };
-                                            let url = subscriber_set.versions
-                                                               .get(version)
-                                                               .unwrap()
-                                                               .image_url_1x
-                                                               .clone();
-                                            println!("BADGE {:?}, URL {}", sub_badge, url);
-                                            sub_badge_url_cache.insert((badge.0.clone(), sub_badge), url);
-                                        }
-                                    }
-                                }
-                                if let Some(url) = sub_badge_url_cache.get(&badge)
-                                {
-                                    let response_opt = tapi.make_raw_request(url);
-                                    if let Ok(mut response) = response_opt
-                                    {
-                                        let mut buffer = Vec::new();
-                                        println!("GOT BADGE DATA FOR URL {:?}", url);
-                                        response.read_to_end(&mut buffer).unwrap();
-                                        tx.send(buffer.clone()).unwrap();
-                                        url_cache.insert(url.clone(), buffer);
-                                    }
-                                }
-                            },
-                            ImageMessage::GetEmote(emote_id, tx) =>
-                            {
-                                /* If emote in emote->bin cache
-                                 *     send(bin)
-                                 * else
-                                 *     get emote image
-                                 *     add emote to emote->bin cache
-                                 *     send(bin)
-                                 */
-                                let mut emote_cache = emote_cache_clone.lock().unwrap();
-
-                                if let Some(bin) = emote_cache.get(&emote_id)
-                                {
-                                    tx.send(bin.clone()).unwrap();
-                                    continue;
-                                }
-
-                                println!("GETTING EMOTE {}", emote_id);
-                                let emote_image = tapi.get_emoticon_image(emote_id, 1).unwrap();
-                                tx.send(emote_image.clone()).unwrap();
-                                emote_cache.insert(emote_id, emote_image);
-                            },
-                            ImageMessage::GetEmoteSet(emote_set, tx) =>
-                            {
-                                /* If emote in emote->bin cache
-                                 *     send(bin)
-                                 * else
-                                 *     get emote image
-                                 *     add emote to emote->bin cache
-                                 *     send(bin)
-                                 */
-                                let mut emote_set_cache = emote_set_cache_clone.lock().unwrap();
-
-                                if let Some(set) = emote_set_cache.get(&emote_set)
-                                {
-                                    tx.send(set.clone()).unwrap();
-                                    continue;
-                                }
-
-                                println!("GETTING EMOTE SET {}", emote_set);
-                                let emoticon_set = tapi.get_emoticons(vec![emote_set]).unwrap();
-                                let twitch_emotes: Vec<_> = emoticon_set.emoticon_sets
-                                                                        .values()
-                                                                        .flat_map(|v| v.iter())
-                                                                        .map(|e| TwitchEmote {
-                                                                            id: e.id,
-                                                                            code: e.code.clone(),
-                                                                        })
-                                                                        .collect();
-                                tx.send(twitch_emotes.clone()).unwrap();
-                                emote_set_cache.insert(emote_set, twitch_emotes);
-                            },
-                        }
-                    },
-                    Err(_) => break,
+                                shared_clone.missing.lock().unwrap().insert(key.clone());
+                            }
+                            shared_clone.images_in_flight.finish(&key, image);
+                        },
+                        ImageMessage::GetEmoteSet(emote_set) =>
+                        {
+                            let twitch_emotes = shared_clone.fetch_emote_set(emote_set);
+                            shared_clone.emote_sets_in_flight.finish(&emote_set, twitch_emotes);
+                        },
+                    }
                 }
-            }
-        });
+            });
+        }
 
         TwitchImageLoader
         {
-            badge_url_cache: badge_url_cache,
-            sub_badge_url_cache: sub_badge_url_cache,
-            url_cache: url_cache,
-            emote_cache: emote_cache,
-            emote_set_cache: emote_set_cache,
+            shared: shared,
             sender: tx,
         }
     }
 
-    pub fn get_badge(&mut self, badge: TwitchBadge) -> Receiver<Vec<u8>>
+    pub fn get_badge(&mut self, badge: TwitchBadge) -> Receiver<ImageResult<Vec<u8>>>
+    {
+        let url = self.shared.badge_url_cache.lock().unwrap().get(&badge).cloned();
+        let cached = url.and_then(|url| self.shared.url_cache.lock().unwrap().get(&url).cloned());
+        self.request_image(ImageKey::Badge(badge), cached)
+    }
+
+    pub fn get_subscriber_badge(&mut self, badge: TwitchBadge, chan: String) -> Receiver<ImageResult<Vec<u8>>>
+    {
+        let tuple = (chan, badge);
+        let url = self.shared.sub_badge_url_cache.lock().unwrap().get(&tuple).cloned();
+        let cached = url.and_then(|url| self.shared.url_cache.lock().unwrap().get(&url).cloned());
+        self.request_image(ImageKey::SubBadge(tuple.0, tuple.1), cached)
+    }
+
+    pub fn get_emote(&mut self, emote_id: u64) -> Receiver<ImageResult<Vec<u8>>>
+    {
+        let cached = self.shared.emote_cache.lock().unwrap().get(&emote_id).cloned();
+        self.request_image(ImageKey::Emote(emote_id), cached)
+    }
+
+    pub fn get_emote_set(&mut self, emote_set: u64) -> Receiver<ImageResult<Vec<TwitchEmote>>>
     {
         let (tx, rx) = mpsc::channel();
-        let badge_url_cache = self.badge_url_cache.lock().unwrap();
-        if let Some(url) = badge_url_cache.get(&badge)
+        let cached = self.shared.emote_set_cache.lock().unwrap().get(&emote_set).cloned();
+        match cached
         {
-            println!("FOUND BADGE AND GOT URL {}", url);
-            let url_cache = self.url_cache.lock().unwrap();
-            if let Some(bin) = url_cache.get(url)
-            {
-                println!("FOUND BIN FROM THAT URL");
-                tx.send(bin.clone()).unwrap();
-            }
-            else
+            Some(set) => tx.send(Ok(set)).unwrap(),
+            None =>
             {
-                self.sender.send(ImageMessage::GetBadge(badge, tx)).unwrap();
-            }
-        }
-        else
-        {
-            println!("FOUND NOTHING");
-            self.sender.send(ImageMessage::GetBadge(badge, tx)).unwrap();
+                if self.shared.emote_sets_in_flight.join(emote_set, tx) &&
+                   self.sender.send(ImageMessage::GetEmoteSet(emote_set)).is_err()
+                {
+                    self.shared.emote_sets_in_flight.finish(&emote_set, Err(workers_gone()));
+                }
+            },
         }
         rx
     }
 
-    pub fn get_subscriber_badge(&mut self, badge: TwitchBadge, chan: String) -> Receiver<Vec<u8>>
+    /* The image for the cheer's tier, usually an animated GIF */
+    pub fn get_cheermote(&mut self, cheer: &TwitchCheer) -> Receiver<ImageResult<Vec<u8>>>
+    {
+        let cheermote = (cheer.prefix.clone(), cheer.tier());
+        let cached = self.shared.cheermote_cache.lock().unwrap().get(&cheermote).cloned();
+        self.request_image(ImageKey::Cheermote(cheermote.0, cheermote.1), cached)
+    }
+
+    /* For the in-memory image caches, by name */
+    pub fn cache_stats(&self) -> Vec<(&'static str, CacheStats)>
+    {
+        vec![("badges", self.shared.url_cache.lock().unwrap().stats()),
+             ("emotes", self.shared.emote_cache.lock().unwrap().stats()),
+             ("cheermotes", self.shared.cheermote_cache.lock().unwrap().stats())]
+    }
+
+    /* Answers from memory when possible, otherwise joins or starts a fetch.
+     * Images known to be missing fail straight away.
+     */
+    fn request_image(&self, key: ImageKey, cached: Option<Vec<u8>>) -> Receiver<ImageResult<Vec<u8>>>
     {
         let (tx, rx) = mpsc::channel();
-        let sub_badge_url_cache = self.sub_badge_url_cache.lock().unwrap();
-        let tuple = (chan, badge);
-        if let Some(url) = sub_badge_url_cache.get(&tuple)
+        if let Some(image) = cached
+        {
+            tx.send(Ok(image)).unwrap();
+        }
+        else if self.shared.missing.lock().unwrap().contains(&key)
+        {
+            tx.send(Err(ImageError::NotFound)).unwrap();
+        }
+        else if self.shared.images_in_flight.join(key.clone(), tx)
         {
-            println!("FOUND BADGE AND GOT URL {}", url);
-            let url_cache = self.url_cache.lock().unwrap();
-            if let Some(bin) = url_cache.get(url)
+            if self.sender.send(ImageMessage::GetImage(key.clone())).is_err()
             {
-                println!("FOUND BIN FROM THAT URL");
-                tx.send(bin.clone()).unwrap();
+                self.shared.images_in_flight.finish(&key, Err(workers_gone()));
             }
-            else
+        }
+        rx
+    }
+}
+
+impl Shared
+{
+    fn fetch_image(&self, key: &ImageKey) -> ImageResult<Vec<u8>>
+    {
+        match *key
+        {
+            ImageKey::Badge(ref badge) =>
             {
-                self.sender.send(ImageMessage::GetSubBadge(tuple, tx)).unwrap();
-            }
+                let url = try!(self.badge_url(badge));
+                self.fetch_url(&url)
+            },
+            ImageKey::SubBadge(ref channel, ref badge) =>
+            {
+                let url = try!(self.sub_badge_url(channel, badge));
+                self.fetch_url(&url)
+            },
+            ImageKey::Emote(emote_id) =>
+            {
+                let image = try!(self.with_retries(|| self.source.emote(emote_id)));
+                self.emote_cache.lock().unwrap().insert(emote_id, image.clone());
+                Ok(image)
+            },
+            ImageKey::Cheermote(ref prefix, tier) =>
+            {
+                let image = try!(self.with_retries(|| self.source.cheermote(prefix, tier)));
+                self.cheermote_cache.lock().unwrap().insert((prefix.clone(), tier), image.clone());
+                Ok(image)
+            },
         }
-        else
+    }
+
+    /* Badges with the same image share it through the URL */
+    fn fetch_url(&self, url: &str) -> ImageResult<Vec<u8>>
+    {
+        let cached = self.url_cache.lock().unwrap().get(&String::from(url)).cloned();
+        if let Some(image) = cached
         {
-            println!("FOUND NOTHING");
-            self.sender.send(ImageMessage::GetSubBadge(tuple, tx)).unwrap();
+            return Ok(image);
         }
-        rx
+        let image = try!(self.with_retries(|| self.source.image(url)));
+        self.url_cache.lock().unwrap().insert(String::from(url), image.clone());
+        Ok(image)
     }
 
-    pub fn get_emote(&mut self, emote_id: u64) -> Receiver<Vec<u8>>
+    fn badge_url(&self, badge: &TwitchBadge) -> ImageResult<String>
     {
-        let (tx, rx) = mpsc::channel();
-        let emote_cache = self.emote_cache.lock().unwrap();
-        if let Some(bin) = emote_cache.get(&emote_id)
+        let url = self.badge_url_cache.lock().unwrap().get(badge).cloned();
+        if let Some(url) = url
         {
-            tx.send(bin.clone()).unwrap();
+            return Ok(url);
         }
-        else
+
+        try!(self.load_list(ListKey::GlobalBadges, || {
+            let badges = try!(self.with_retries(|| self.source.badge_sets()));
+            let mut badge_url_cache = self.badge_url_cache.lock().unwrap();
+            for (badge, url) in badges
+            {
+                badge_url_cache.insert(badge, url);
+            }
+            Ok(())
+        }));
+        self.badge_url_cache.lock().unwrap().get(badge).cloned().ok_or(ImageError::NotFound)
+    }
+
+    fn sub_badge_url(&self, channel: &str, badge: &TwitchBadge) -> ImageResult<String>
+    {
+        let key = (String::from(channel), badge.clone());
+        let url = self.sub_badge_url_cache.lock().unwrap().get(&key).cloned();
+        if let Some(url) = url
         {
-            self.sender.send(ImageMessage::GetEmote(emote_id, tx)).unwrap();
+            return Ok(url);
         }
-        rx
+
+        try!(self.load_list(ListKey::SubBadges(String::from(channel)), || {
+            let sub_badges = try!(self.with_retries(|| self.source.channel_badges(channel)));
+            let mut sub_badge_url_cache = self.sub_badge_url_cache.lock().unwrap();
+            for (sub_badge, url) in sub_badges
+            {
+                sub_badge_url_cache.insert((String::from(channel), sub_badge), url);
+            }
+            Ok(())
+        }));
+        self.sub_badge_url_cache.lock().unwrap().get(&key).cloned().ok_or(ImageError::NotFound)
     }
 
-    pub fn get_emote_set(&mut self, emote_set: u64) -> Receiver<Vec<TwitchEmote>>
+    /* Runs load unless another worker already is, then waits for it */
+    fn load_list<F>(&self, key: ListKey, load: F) -> ImageResult<()>
+        where F: FnOnce() -> ImageResult<()>
     {
         let (tx, rx) = mpsc::channel();
-        let emote_set_cache = self.emote_set_cache.lock().unwrap();
-        if let Some(set) = emote_set_cache.get(&emote_set)
+        if self.lists_in_flight.join(key.clone(), tx)
         {
-            tx.send(set.clone()).unwrap();
+            let loaded = load();
+            self.lists_in_flight.finish(&key, loaded);
         }
-        else
+        rx.recv().unwrap_or(Err(workers_gone()))
+    }
+
+    fn fetch_emote_set(&self, emote_set: u64) -> ImageResult<Vec<TwitchEmote>>
+    {
+        let twitch_emotes = try!(self.with_retries(|| self.source.emote_set(emote_set)));
+        self.emote_set_cache.lock().unwrap().insert(emote_set, twitch_emotes.clone());
+        Ok(twitch_emotes)
+    }
+
+    /* Network errors are often gone a moment later, NotFound never is */
+    fn with_retries<T, F>(&self, fetch: F) -> ImageResult<T>
+        where F: Fn() -> ImageResult<T>
+    {
+        let mut delay = self.retry_delay;
+        let mut result = fetch();
+        for _ in 0..RETRIES
         {
-            self.sender.send(ImageMessage::GetEmoteSet(emote_set, tx)).unwrap();
+            match result
+            {
+                Err(ImageError::Network(_)) => (),
+                _ => break,
+            }
+            thread::sleep(delay);
+            delay = delay * 2;
+            result = fetch();
         }
-        rx
+        result
+    }
+}
+
+/* Every worker has panicked, nothing is left to fetch the image */
+fn workers_gone() -> ImageError
+{
+    ImageError::Network(String::from("the image loader stopped"))
+}
+
+#[cfg(test)]
+fn test_loader(source: &Arc<::fixture_source::FixtureSource>) -> TwitchImageLoader
+{
+    TwitchImageLoader::start(source.clone(), Duration::from_millis(0))
+}
+
+#[test]
+fn test_image_loader_caches_images()
+{
+    use fixture_source::FixtureSource;
+
+    let mut source = FixtureSource::default();
+    source.images.insert(String::from("emote/25"), vec![1, 2, 3]);
+    let source = Arc::new(source);
+    let mut loader = test_loader(&source);
+
+    assert_eq!(loader.get_emote(25).recv().unwrap(), Ok(vec![1, 2, 3]));
+    assert_eq!(loader.get_emote(25).recv().unwrap(), Ok(vec![1, 2, 3]));
+    assert_eq!(source.requests(), vec!["emote/25"]);
+    assert_eq!(loader.cache_stats()[1].1.hits, 1);
+}
+
+#[test]
+fn test_image_loader_remembers_missing_images()
+{
+    use fixture_source::FixtureSource;
+
+    let source = Arc::new(FixtureSource::default());
+    let mut loader = test_loader(&source);
+
+    assert_eq!(loader.get_emote(7).recv().unwrap(), Err(ImageError::NotFound));
+    assert_eq!(loader.get_emote(7).recv().unwrap(), Err(ImageError::NotFound));
+    assert_eq!(source.requests(), vec!["emote/7"]);
+}
+
+#[test]
+fn test_image_loader_retries_network_errors()
+{
+    use fixture_source::FixtureSource;
+
+    let mut source = FixtureSource::default();
+    source.images.insert(String::from("cheermote/cheer/100"), vec![4]);
+    let source = Arc::new(source);
+    let mut loader = test_loader(&source);
+    let cheer = TwitchCheer { prefix: String::from("cheer"), amount: 150, range: (0, 8) };
+
+    *source.failures.lock().unwrap() = vec![ImageError::Network(String::from("timed out")); 2];
+    assert_eq!(loader.get_cheermote(&cheer).recv().unwrap(), Ok(vec![4]));
+    assert_eq!(source.requests().len(), 3);
+
+    /* Giving up is not remembered like NotFound is */
+    let mut emote_source = FixtureSource::default();
+    emote_source.images.insert(String::from("emote/9"), vec![5]);
+    let emote_source = Arc::new(emote_source);
+    let mut emote_loader = test_loader(&emote_source);
+    *emote_source.failures.lock().unwrap() = vec![ImageError::Network(String::from("timed out")); RETRIES as usize + 1];
+    assert_eq!(emote_loader.get_emote(9).recv().unwrap(), Err(ImageError::Network(String::from("timed out"))));
+    assert_eq!(emote_loader.get_emote(9).recv().unwrap(), Ok(vec![5]));
+    assert_eq!(emote_source.requests().len(), RETRIES as usize + 2);
+}
+
+#[test]
+fn test_image_loader_finds_badges_through_lists()
+{
+    use fixture_source::FixtureSource;
+    use std::str::FromStr;
+
+    let badge = |name| TwitchBadge::from_str(name).unwrap();
+    let mut source = FixtureSource::default();
+    source.badge_sets = vec![(badge("moderator/1"), String::from("https://badges/mod")),
+                             (badge("partner/1"), String::from("https://badges/mod"))];
+    source.channel_badges.insert(String::from("channel"), vec![(badge("subscriber/0"), String::from("https://badges/sub"))]);
+    source.images.insert(String::from("https://badges/mod"), vec![6]);
+    source.images.insert(String::from("https://badges/sub"), vec![7]);
+    let source = Arc::new(source);
+    let mut loader = test_loader(&source);
+
+    assert_eq!(loader.get_badge(badge("moderator/1")).recv().unwrap(), Ok(vec![6]));
+    /* Same list, same URL */
+    assert_eq!(loader.get_badge(badge("partner/1")).recv().unwrap(), Ok(vec![6]));
+    assert_eq!(loader.get_subscriber_badge(badge("subscriber/0"), String::from("channel")).recv().unwrap(), Ok(vec![7]));
+    assert_eq!(source.requests(), vec!["badges", "https://badges/mod", "badges/channel", "https://badges/sub"]);
+
+    assert_eq!(loader.get_subscriber_badge(badge("subscriber/0"), String::from("nobody")).recv().unwrap(),
+               Err(ImageError::NotFound));
+}
+
+#[test]
+fn test_image_loader_caches_emote_sets()
+{
+    use fixture_source::FixtureSource;
+
+    let mut source = FixtureSource::default();
+    source.emote_sets.insert(0, vec![TwitchEmote { id: 25, code: String::from("Kappa") }]);
+    let source = Arc::new(source);
+    let mut loader = test_loader(&source);
+
+    for _ in 0..2
+    {
+        let emotes = loader.get_emote_set(0).recv().unwrap().unwrap();
+        assert_eq!(emotes.len(), 1);
+        assert_eq!(emotes[0].code, "Kappa");
     }
+    assert_eq!(loader.get_emote_set(1).recv().unwrap().map(|emotes| emotes.len()), Err(ImageError::NotFound));
+    assert_eq!(source.requests(), vec!["emote-set/0", "emote-set/1"]);
 }
@@ -1,9 +1,11 @@
 use twitch_chat::message::Message;
 
 use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::str::FromStr;
 use std::u8;
+use std::u16;
 use std::u64;
 use std::usize;
 
@@ -28,12 +30,28 @@ const CHAT_COLORS: [UserColor; 15] = [
 #[derive(Clone,Debug)]
 pub struct TwitchPrivmsg
 {
+    /* Empty for locally echoed messages */
+    pub id: String,
+    pub user: String,
     pub name: String,
     pub emotes: Vec<TwitchEmoteRange>,
     pub badges: Vec<TwitchBadge>,
     pub color: UserColor,
     pub to: String,
     pub message: String,
+    /* Bits cheered with this message, 0 for none */
+    pub bits: u64,
+    pub reply: Option<TwitchReply>,
+}
+
+/* The message a reply answers, from the reply-parent tags */
+#[derive(Clone,Debug,PartialEq)]
+pub struct TwitchReply
+{
+    pub parent_id: String,
+    pub parent_user: String,
+    pub parent_name: String,
+    pub parent_body: String,
 }
 
 #[derive(Clone,Debug)]
@@ -47,6 +65,148 @@ pub struct TwitchUserState
     pub user_type: (),
 }
 
+impl TwitchUserState
+{
+    /* Broadcasters, moderators and VIPs are exempt from slow mode */
+    pub fn is_privileged(&self) -> bool
+    {
+        self.badges.iter()
+                   .any(|b| b.set == "broadcaster" || b.set == "moderator" || b.set == "vip")
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct TwitchRoomState
+{
+    pub emote_only: Option<bool>,
+    /* -1 when disabled, otherwise the minimum follow time in minutes */
+    pub followers_only: Option<i64>,
+    pub r9k: Option<bool>,
+    /* Seconds between messages, 0 when disabled */
+    pub slow: Option<u64>,
+    pub subs_only: Option<bool>,
+    pub room_id: u64,
+}
+
+impl TwitchRoomState
+{
+    /* ROOMSTATE after the initial join only carries the tags that changed */
+    pub fn update(&mut self, other: &TwitchRoomState)
+    {
+        if other.emote_only.is_some()
+        {
+            self.emote_only = other.emote_only;
+        }
+        if other.followers_only.is_some()
+        {
+            self.followers_only = other.followers_only;
+        }
+        if other.r9k.is_some()
+        {
+            self.r9k = other.r9k;
+        }
+        if other.slow.is_some()
+        {
+            self.slow = other.slow;
+        }
+        if other.subs_only.is_some()
+        {
+            self.subs_only = other.subs_only;
+        }
+        if other.room_id != 0
+        {
+            self.room_id = other.room_id;
+        }
+    }
+
+    pub fn describe(&self) -> String
+    {
+        let mut modes = Vec::new();
+        if let Some(slow) = self.slow
+        {
+            if slow > 0
+            {
+                modes.push(format!("slow {} s", slow));
+            }
+        }
+        if self.subs_only == Some(true)
+        {
+            modes.push("sub-only".into());
+        }
+        if self.emote_only == Some(true)
+        {
+            modes.push("emote-only".into());
+        }
+        if let Some(minutes) = self.followers_only
+        {
+            if minutes == 0
+            {
+                modes.push("followers-only".into());
+            }
+            else if minutes > 0
+            {
+                modes.push(format!("followers-only {} m", minutes));
+            }
+        }
+        if self.r9k == Some(true)
+        {
+            modes.push("r9k".into());
+        }
+        modes.join(", ")
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct TwitchClearChat
+{
+    pub channel: String,
+    /* None when the whole channel was cleared */
+    pub target: Option<String>,
+    /* None for a permanent ban */
+    pub ban_duration: Option<u64>,
+    pub ban_reason: Option<String>,
+    pub room_id: u64,
+}
+
+#[derive(Clone,Debug)]
+pub struct TwitchClearMsg
+{
+    pub channel: String,
+    pub login: String,
+    pub target_msg_id: String,
+    pub message: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct TwitchUserNotice
+{
+    pub channel: String,
+    /* Kind of event, e.g. "sub", "resub", "subgift" or "raid" */
+    pub msg_id: String,
+    pub system_msg: String,
+    /* The message the user attached, if any */
+    pub privmsg: Option<TwitchPrivmsg>,
+}
+
+#[derive(Clone,Debug)]
+pub struct TwitchNotice
+{
+    /* "*" for notices not about a channel, like a failed login */
+    pub channel: String,
+    /* Kind of notice, e.g. "msg_slowmode". Missing on some notices. */
+    pub msg_id: Option<String>,
+    pub message: String,
+}
+
+#[derive(Clone,Debug)]
+pub struct TwitchHostTarget
+{
+    pub channel: String,
+    /* None when hosting stopped */
+    pub target: Option<String>,
+    pub viewers: Option<u64>,
+}
+
 #[derive(Clone,Debug)]
 pub enum TwitchMessage
 {
@@ -54,177 +214,412 @@ pub enum TwitchMessage
     TwitchEcho(TwitchPrivmsg, Vec<u64>),
     TwitchGlobalUserState(TwitchUserState),
     TwitchUserState(String, TwitchUserState),
+    TwitchRoomState(String, TwitchRoomState),
+    TwitchClearChat(TwitchClearChat),
+    TwitchClearMsg(TwitchClearMsg),
+    TwitchUserNotice(TwitchUserNotice),
+    TwitchWhisper(TwitchPrivmsg),
+    TwitchNotice(TwitchNotice),
+    TwitchHostTarget(TwitchHostTarget),
+    /* Channel and the login of who joined or left */
+    TwitchJoin(String, String),
+    TwitchPart(String, String),
+    TwitchPing(String),
+    TwitchReconnect,
+    /* 353, a channel and some of the users in it */
+    TwitchNames(String, Vec<String>),
+    /* 366, the end of the 353 replies for a channel */
+    TwitchEndOfNames(String),
+    /* Any other numeric reply, with its text */
+    TwitchNumeric(u16, String),
     Unknown(String),
 }
 
+/* The parts of an IRC line a TwitchMessage is built from. Kept apart from
+ * twitch_chat's Message so lines can be parsed without a connection.
+ */
+pub struct IrcLine
+{
+    pub raw: String,
+    /* Values still escaped, as they were sent */
+    pub tags: HashMap<String, String>,
+    pub from: Option<String>,
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl From<Message> for IrcLine
+{
+    fn from(msg: Message) -> Self
+    {
+        IrcLine
+        {
+            raw: msg.raw,
+            tags: msg.tags,
+            from: msg.from,
+            command: msg.command,
+            args: msg.args,
+        }
+    }
+}
+
+impl IrcLine
+{
+    fn arg(&self, idx: usize) -> String
+    {
+        self.args.get(idx)
+                 .map(|s| s.clone())
+                 .unwrap_or(String::new())
+    }
+}
+
+/* Reads typed values out of the tags of a line, unescaping them */
+pub struct Tags<'a>
+{
+    tags: &'a HashMap<String, String>,
+}
+
+impl<'a> Tags<'a>
+{
+    pub fn new(tags: &'a HashMap<String, String>) -> Tags<'a>
+    {
+        Tags
+        {
+            tags: tags,
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Option<String>
+    {
+        self.tags.get(key)
+                 .map(|value| unescape_tag_value(value))
+    }
+
+    /* Twitch sends some tags with an empty value instead of leaving them out */
+    pub fn non_empty(&self, key: &str) -> Option<String>
+    {
+        self.string(key)
+            .and_then(|value| if value.is_empty()
+                 {
+                     None
+                 }
+                 else
+                 {
+                     Some(value)
+                 })
+    }
+
+    pub fn number<T: FromStr>(&self, key: &str) -> Option<T>
+    {
+        self.tags.get(key)
+                 .and_then(|value| T::from_str(value).ok())
+    }
+
+    /* "0" or "1" */
+    pub fn flag(&self, key: &str) -> Option<bool>
+    {
+        self.number::<u8>(key)
+            .map(|n| n != 0)
+    }
+
+    /* A list like "a,b,c", skipping entries that do not parse */
+    pub fn list<T: FromStr>(&self, key: &str, separator: &str) -> Vec<T>
+    {
+        self.string(key)
+            .unwrap_or(String::new())
+            .split(separator)
+            .filter_map(|item| T::from_str(item).ok())
+            .collect()
+    }
+
+    pub fn badges(&self) -> Vec<TwitchBadge>
+    {
+        self.list("badges", ",")
+    }
+
+    pub fn emotes(&self) -> Vec<TwitchEmoteRange>
+    {
+        self.list("emotes", "/")
+    }
+
+    pub fn emote_sets(&self) -> Vec<u64>
+    {
+        self.list("emote-sets", ",")
+    }
+
+    /* Falls back to the nick from the prefix when the tag is empty */
+    pub fn display_name(&self, from: &str) -> String
+    {
+        self.non_empty("display-name")
+            .unwrap_or_else(|| get_name_from_prefix(from))
+    }
+
+    /* Users who never picked a color get one derived from their name */
+    pub fn color(&self, name: &str) -> UserColor
+    {
+        self.tags.get("color")
+                 .and_then(|value| UserColor::from_str(value).ok())
+                 .unwrap_or_else(|| UserColor::from_name(name))
+    }
+
+    /* Only set on replies, the parent's login names the user when the
+     * display name is missing.
+     */
+    pub fn reply(&self) -> Option<TwitchReply>
+    {
+        self.non_empty("reply-parent-msg-id")
+            .map(|parent_id| {
+                let parent_user = self.string("reply-parent-user-login")
+                                      .unwrap_or(String::new());
+                TwitchReply {
+                    parent_id: parent_id,
+                    parent_name: self.non_empty("reply-parent-display-name")
+                                     .unwrap_or_else(|| parent_user.clone()),
+                    parent_user: parent_user,
+                    parent_body: self.string("reply-parent-msg-body")
+                                     .unwrap_or(String::new()),
+                }
+            })
+    }
+
+    pub fn user_state(&self, from: &str) -> TwitchUserState
+    {
+        let name = self.display_name(from);
+        TwitchUserState {
+            badges: self.badges(),
+            color: self.color(&name),
+            display_name: name,
+            emote_sets: self.emote_sets(),
+            user_id: self.number("user-id").unwrap_or(0),
+            user_type: (),
+        }
+    }
+}
+
 impl From<Message> for TwitchMessage
 {
     fn from(msg: Message) -> Self
     {
+        TwitchMessage::from(IrcLine::from(msg))
+    }
+}
+
+impl From<IrcLine> for TwitchMessage
+{
+    fn from(msg: IrcLine) -> Self
+    {
+        let tags = Tags::new(&msg.tags);
         match msg.command.as_str()
         {
             "PRIVMSG" =>
             {
-                let from = msg.from.unwrap_or(String::new());
-                let badges = msg.tags.get("badges")
-                                     .map(|s| s.as_str())
-                                     .unwrap_or("")
-                                     .split(",")
-                                     .map(TwitchBadge::from_str)
-                                     .filter(|r| r.is_ok())
-                                     .map(|r| r.unwrap())
-                                     .collect();
-                let emotes = msg.tags.get("emotes")
-                                     .map(|s| s.as_str())
-                                     .unwrap_or("")
-                                     .split("/")
-                                     .map(TwitchEmoteRange::from_str)
-                                     .filter(|r| r.is_ok())
-                                     .map(|r| r.unwrap())
-                                     .collect();
-                let name = msg.tags.get("display-name")
-                                   .map(|s| s.clone())
-                                   .map(|s| if s.is_empty()
-                                        {
-                                            get_name_from_prefix(&from)
-                                        }
-                                        else
-                                        {
-                                            s
-                                        })
-                                   .unwrap_or("".into());
-                let color = msg.tags.get("color")
-                                    .map(|s| s.as_str())
-                                    .map(UserColor::from_str)
-                                    .and_then(|r| r.ok())
-                                    .unwrap_or_else(||
-                                        UserColor::from_name(&name));
-                TwitchMessage::TwitchPrivmsg(
-                    TwitchPrivmsg {
-                        name: name,
-                        emotes: emotes,
-                        badges: badges,
-                        color: color,
-                        to: msg.args.get(0)
-                                    .map(|s| s.clone())
-                                    .unwrap_or(String::new()),
-                        message: msg.args.get(1)
-                                         .map(|s| s.clone())
-                                         .unwrap_or(String::new()),
+                TwitchMessage::TwitchPrivmsg(privmsg_from_line(&msg))
+            },
+            "WHISPER" =>
+            {
+                TwitchMessage::TwitchWhisper(privmsg_from_line(&msg))
+            },
+            "USERNOTICE" =>
+            {
+                let privmsg = privmsg_from_line(&msg);
+                TwitchMessage::TwitchUserNotice(
+                    TwitchUserNotice {
+                        channel: privmsg.to.clone(),
+                        msg_id: tags.string("msg-id").unwrap_or(String::new()),
+                        system_msg: tags.string("system-msg").unwrap_or(String::new()),
+                        privmsg: if msg.args.len() > 1
+                        {
+                            Some(privmsg)
+                        }
+                        else
+                        {
+                            None
+                        },
                     }
                 )
             },
             "GLOBALUSERSTATE" =>
             {
-                let from = msg.from.unwrap_or(String::new());
-                let badges = msg.tags.get("badges")
-                                     .map(|s| s.as_str())
-                                     .unwrap_or("")
-                                     .split(",")
-                                     .map(TwitchBadge::from_str)
-                                     .filter(|r| r.is_ok())
-                                     .map(|r| r.unwrap())
-                                     .collect();
-                let emote_sets = msg.tags.get("emote-sets")
-                                         .map(|s| s.as_str())
-                                         .unwrap_or("")
-                                         .split(",")
-                                         .map(u64::from_str)
-                                         .filter(|r| r.is_ok())
-                                         .map(|r| r.unwrap())
-                                         .collect();
-                let name = msg.tags.get("display-name")
-                                   .map(|s| s.clone())
-                                   .map(|s| if s.is_empty()
-                                        {
-                                            get_name_from_prefix(&from)
-                                        }
-                                        else
-                                        {
-                                            s
-                                        })
-                                   .unwrap_or("".into());
-                let color = msg.tags.get("color")
-                                    .map(|s| s.as_str())
-                                    .map(UserColor::from_str)
-                                    .and_then(|r| r.ok())
-                                    .unwrap_or_else(||
-                                        UserColor::from_name(&name));
-                let user_id = msg.tags.get("user-id")
-                                      .map(String::as_str)
-                                      .map(u64::from_str)
-                                      .unwrap_or(Ok(0))
-                                      .unwrap_or(0);
-                TwitchMessage::TwitchGlobalUserState(
-                    TwitchUserState {
-                        badges: badges,
-                        color: color,
-                        display_name: name,
-                        emote_sets: emote_sets,
-                        user_id: user_id,
-                        user_type: (),
+                let from = msg.from.as_ref()
+                                   .map(String::as_str)
+                                   .unwrap_or("");
+                TwitchMessage::TwitchGlobalUserState(tags.user_state(from))
+            },
+            "USERSTATE" =>
+            {
+                let from = msg.from.as_ref()
+                                   .map(String::as_str)
+                                   .unwrap_or("");
+                TwitchMessage::TwitchUserState(msg.arg(0), tags.user_state(from))
+            },
+            "ROOMSTATE" =>
+            {
+                let room_state = TwitchRoomState {
+                    emote_only: tags.flag("emote-only"),
+                    followers_only: tags.number("followers-only"),
+                    r9k: tags.flag("r9k"),
+                    slow: tags.number("slow"),
+                    subs_only: tags.flag("subs-only"),
+                    room_id: tags.number("room-id").unwrap_or(0),
+                };
+                TwitchMessage::TwitchRoomState(msg.arg(0), room_state)
+            },
+            "CLEARCHAT" =>
+            {
+                TwitchMessage::TwitchClearChat(
+                    TwitchClearChat {
+                        channel: msg.arg(0),
+                        target: msg.args.get(1)
+                                        .map(|s| s.clone()),
+                        ban_duration: tags.number("ban-duration"),
+                        ban_reason: tags.non_empty("ban-reason"),
+                        room_id: tags.number("room-id").unwrap_or(0),
                     }
                 )
             },
-            "USERSTATE" =>
+            "CLEARMSG" =>
             {
-                let from = msg.from.unwrap_or(String::new());
-                let badges = msg.tags.get("badges")
-                                     .map(|s| s.as_str())
-                                     .unwrap_or("")
-                                     .split(",")
-                                     .map(TwitchBadge::from_str)
-                                     .filter(|r| r.is_ok())
-                                     .map(|r| r.unwrap())
-                                     .collect();
-                let emote_sets = msg.tags.get("emote-sets")
-                                         .map(|s| s.as_str())
-                                         .unwrap_or("")
-                                         .split(",")
-                                         .map(u64::from_str)
-                                         .filter(|r| r.is_ok())
-                                         .map(|r| r.unwrap())
-                                         .collect();
-                let name = msg.tags.get("display-name")
-                                   .map(|s| s.clone())
-                                   .map(|s| if s.is_empty()
-                                        {
-                                            get_name_from_prefix(&from)
-                                        }
-                                        else
-                                        {
-                                            s
-                                        })
-                                   .unwrap_or("".into());
-                let color = msg.tags.get("color")
-                                    .map(|s| s.as_str())
-                                    .map(UserColor::from_str)
-                                    .and_then(|r| r.ok())
-                                    .unwrap_or_else(||
-                                        UserColor::from_name(&name));
-                let user_id = msg.tags.get("user-id")
-                                      .map(String::as_str)
-                                      .map(u64::from_str)
-                                      .unwrap_or(Ok(0))
-                                      .unwrap_or(0);
-                let chan = msg.args.get(0)
-                                   .map(|s| s.clone())
-                                   .unwrap_or(String::new());
-                TwitchMessage::TwitchUserState(
-                    chan,
-                    TwitchUserState {
-                        badges: badges,
-                        color: color,
-                        display_name: name,
-                        emote_sets: emote_sets,
-                        user_id: user_id,
-                        user_type: (),
+                TwitchMessage::TwitchClearMsg(
+                    TwitchClearMsg {
+                        channel: msg.arg(0),
+                        login: tags.string("login").unwrap_or(String::new()),
+                        target_msg_id: tags.string("target-msg-id").unwrap_or(String::new()),
+                        message: msg.arg(1),
                     }
                 )
             },
-            _ => TwitchMessage::Unknown(msg.raw)
+            "NOTICE" =>
+            {
+                TwitchMessage::TwitchNotice(
+                    TwitchNotice {
+                        channel: msg.arg(0),
+                        msg_id: tags.string("msg-id"),
+                        message: msg.arg(1),
+                    }
+                )
+            },
+            "HOSTTARGET" =>
+            {
+                /* The second argument is "<target> [viewers]", target "-" for no one */
+                let target_arg = msg.arg(1);
+                let mut split = target_arg.split(' ');
+                let target = split.next()
+                                  .and_then(|t| if t == "-" || t.is_empty()
+                                       {
+                                           None
+                                       }
+                                       else
+                                       {
+                                           Some(String::from(t))
+                                       });
+                let viewers = split.next()
+                                   .and_then(|v| u64::from_str(v).ok());
+                TwitchMessage::TwitchHostTarget(
+                    TwitchHostTarget {
+                        channel: msg.arg(0),
+                        target: target,
+                        viewers: viewers,
+                    }
+                )
+            },
+            "JOIN" | "PART" =>
+            {
+                let user = get_login_from_prefix(msg.from.as_ref()
+                                                         .map(String::as_str)
+                                                         .unwrap_or(""));
+                if msg.command == "JOIN"
+                {
+                    TwitchMessage::TwitchJoin(msg.arg(0), user)
+                }
+                else
+                {
+                    TwitchMessage::TwitchPart(msg.arg(0), user)
+                }
+            },
+            "PING" => TwitchMessage::TwitchPing(msg.arg(0)),
+            "RECONNECT" => TwitchMessage::TwitchReconnect,
+            "353" =>
+            {
+                /* "<nick> = <channel> :<names>" */
+                let names = msg.arg(3)
+                               .split(' ')
+                               .filter(|name| !name.is_empty())
+                               .map(String::from)
+                               .collect();
+                TwitchMessage::TwitchNames(msg.arg(2), names)
+            },
+            /* "<nick> <channel> :End of /NAMES list" */
+            "366" => TwitchMessage::TwitchEndOfNames(msg.arg(1)),
+            command =>
+            {
+                match u16::from_str(command)
+                {
+                    Ok(numeric) if command.len() == 3 =>
+                    {
+                        /* The first argument is our own nick */
+                        let text = msg.args.iter()
+                                           .skip(1)
+                                           .cloned()
+                                           .collect::<Vec<_>>()
+                                           .join(" ");
+                        TwitchMessage::TwitchNumeric(numeric, text)
+                    },
+                    _ => TwitchMessage::Unknown(msg.raw.clone()),
+                }
+            },
+        }
+    }
+}
+
+/* Shared by PRIVMSG, WHISPER and the attached message of a USERNOTICE */
+fn privmsg_from_line(msg: &IrcLine) -> TwitchPrivmsg
+{
+    let tags = Tags::new(&msg.tags);
+    /* USERNOTICE comes from tmi.twitch.tv and names the user in a tag */
+    let from = tags.string("login")
+                   .or_else(|| msg.from.clone())
+                   .unwrap_or(String::new());
+    let name = tags.display_name(&from);
+    TwitchPrivmsg {
+        id: tags.string("id").unwrap_or(String::new()),
+        user: get_login_from_prefix(&from),
+        color: tags.color(&name),
+        name: name,
+        emotes: tags.emotes(),
+        badges: tags.badges(),
+        to: msg.arg(0),
+        message: msg.arg(1),
+        bits: tags.number("bits").unwrap_or(0),
+        reply: tags.reply(),
+    }
+}
+
+/* Undoes IRCv3 tag value escaping, e.g. "\s" for a space */
+pub fn unescape_tag_value(value: &str) -> String
+{
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next()
+    {
+        if c == '\\'
+        {
+            match chars.next()
+            {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('\\') => unescaped.push('\\'),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => {},
+            }
+        }
+        else
+        {
+            unescaped.push(c);
         }
     }
+    unescaped
 }
 
 #[derive(Copy,Clone,Debug)]
@@ -260,6 +655,22 @@ impl FromStr for UserColor
     }
 }
 
+/* Channels are compared as lowercase names starting with '#', so
+ * "OnVar", "#OnVar" and "#onvar" are all the same channel.
+ */
+pub fn normalize_channel(name: &str) -> String
+{
+    format!("#{}", name.trim()
+                       .trim_left_matches('#')
+                       .to_lowercase())
+}
+
+/* The channel name without its '#', which is the broadcaster's login */
+pub fn channel_login(channel: &str) -> &str
+{
+    channel.trim_left_matches('#')
+}
+
 fn get_name_from_prefix(prefix: &str) -> String
 {
     let nick = prefix.split("!")
@@ -273,6 +684,14 @@ fn get_name_from_prefix(prefix: &str) -> String
     }
 }
 
+fn get_login_from_prefix(prefix: &str) -> String
+{
+    prefix.split("!")
+          .next()
+          .unwrap_or("")
+          .to_lowercase()
+}
+
 #[derive(Clone,Debug,Eq,PartialEq,Hash)]
 pub struct TwitchBadge
 {
@@ -336,6 +755,64 @@ impl FromStr for TwitchEmoteRange
     }
 }
 
+impl TwitchEmoteRange
+{
+    /* Turns the inclusive ranges from the emotes tag into half-open char
+     * (code point) ranges into message, which is what the text buffer counts.
+     * Twitch counts code points, but some servers and bots count UTF-16
+     * units, which differs once an emoji comes first. A range is read as
+     * code points when that lands on a whole word, then as UTF-16 units,
+     * and dropped if neither fits.
+     */
+    pub fn char_ranges(&self, message: &str) -> Vec<(usize, usize)>
+    {
+        let chars: Vec<char> = message.chars().collect();
+        /* Char index where each UTF-16 unit starts, None inside a surrogate pair */
+        let mut utf16_to_char = Vec::with_capacity(chars.len() + 1);
+        for (idx, c) in chars.iter().enumerate()
+        {
+            utf16_to_char.push(Some(idx));
+            if c.len_utf16() == 2
+            {
+                utf16_to_char.push(None);
+            }
+        }
+        utf16_to_char.push(Some(chars.len()));
+
+        self.ranges.iter()
+                   .filter(|&&(start, end)| start <= end)
+                   .filter_map(|&(start, end)| {
+                       let after = match end.checked_add(1)
+                       {
+                           Some(after) => after,
+                           None => return None,
+                       };
+                       let code_points = (start, after);
+                       if is_whole_word(&chars, code_points)
+                       {
+                           return Some(code_points);
+                       }
+                       match (utf16_to_char.get(start), utf16_to_char.get(after))
+                       {
+                           (Some(&Some(start)), Some(&Some(end))) if is_whole_word(&chars, (start, end)) =>
+                               Some((start, end)),
+                           _ => None,
+                       }
+                   })
+                   .collect()
+    }
+}
+
+/* Emotes are always a whole space separated word */
+fn is_whole_word(chars: &[char], (start, end): (usize, usize)) -> bool
+{
+    start < end
+        && end <= chars.len()
+        && !chars[start..end].iter().any(|c| c.is_whitespace())
+        && (start == 0 || chars[start - 1].is_whitespace())
+        && (end == chars.len() || chars[end].is_whitespace())
+}
+
 #[derive(Clone,Debug)]
 pub struct TwitchEmote
 {
@@ -343,8 +820,424 @@ pub struct TwitchEmote
     pub code: String,
 }
 
+/* A cheermote like "Cheer100" in a message that has bits */
+#[derive(Clone,Debug,PartialEq)]
+pub struct TwitchCheer
+{
+    /* Lowercase, as used in image URLs */
+    pub prefix: String,
+    pub amount: u64,
+    /* Half-open char (code point) range of the whole word */
+    pub range: (usize, usize),
+}
+
+impl TwitchCheer
+{
+    pub fn tier(&self) -> u64
+    {
+        cheer_tier(self.amount)
+    }
+
+    pub fn color(&self) -> UserColor
+    {
+        cheer_color(self.amount)
+    }
+}
+
+/* Cheermote images and colors change at these amounts */
+pub fn cheer_tier(amount: u64) -> u64
+{
+    [10000, 5000, 1000, 100].iter()
+                            .cloned()
+                            .find(|&tier| amount >= tier)
+                            .unwrap_or(1)
+}
+
+pub fn cheer_color(amount: u64) -> UserColor
+{
+    match cheer_tier(amount)
+    {
+        1 => UserColor(0x97, 0x97, 0x97),
+        100 => UserColor(0x9C, 0x3E, 0xE8),
+        1000 => UserColor(0x1D, 0xB2, 0xA5),
+        5000 => UserColor(0x00, 0x99, 0xFE),
+        _ => UserColor(0xF4, 0x30, 0x21),
+    }
+}
+
 #[test]
 fn test_get_name_from_prefix()
 {
     assert_eq!(get_name_from_prefix("name!name@name.tmi.twitch.tv"), String::from("Name"));
 }
+
+#[test]
+fn test_normalize_channel()
+{
+    assert_eq!(normalize_channel("OnVar"), String::from("#onvar"));
+    assert_eq!(normalize_channel(" #OnVar "), String::from("#onvar"));
+    assert_eq!(channel_login("#onvar"), "onvar");
+}
+
+#[test]
+fn test_get_login_from_prefix()
+{
+    assert_eq!(get_login_from_prefix("Name!name@name.tmi.twitch.tv"), String::from("name"));
+}
+
+#[test]
+fn test_unescape_tag_value()
+{
+    assert_eq!(unescape_tag_value("5\\sraiders\\sfrom\\sOnVar\\:\\\\"), String::from("5 raiders from OnVar;\\"));
+}
+
+/* Splits a raw line the way twitch_chat does, for testing against lines
+ * recorded from the server.
+ */
+#[cfg(test)]
+fn parse_raw(raw: &str) -> TwitchMessage
+{
+    let mut rest = raw;
+    let mut tags = HashMap::new();
+    if rest.starts_with("@")
+    {
+        let end = rest.find(' ').unwrap();
+        for tag in rest[1..end].split(';')
+        {
+            let mut split = tag.splitn(2, '=');
+            tags.insert(String::from(split.next().unwrap()), String::from(split.next().unwrap_or("")));
+        }
+        rest = &rest[end + 1..];
+    }
+    let mut from = None;
+    if rest.starts_with(":")
+    {
+        let end = rest.find(' ').unwrap();
+        from = Some(String::from(&rest[1..end]));
+        rest = &rest[end + 1..];
+    }
+    let (params, trailing) = match rest.find(" :")
+    {
+        Some(idx) => (&rest[..idx], Some(&rest[idx + 2..])),
+        None => (rest, None),
+    };
+    let mut words = params.split(' ');
+    let command = String::from(words.next().unwrap());
+    let mut args: Vec<String> = words.map(String::from).collect();
+    if let Some(trailing) = trailing
+    {
+        args.push(String::from(trailing));
+    }
+    TwitchMessage::from(IrcLine {
+        raw: String::from(raw),
+        tags: tags,
+        from: from,
+        command: command,
+        args: args,
+    })
+}
+
+#[test]
+fn test_parse_privmsg()
+{
+    let raw = "@badge-info=subscriber/8;badges=broadcaster/1,subscriber/6;color=#0D4200;display-name=OnVar;emotes=25:0-4,12-16/1902:6-10;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=1337;subscriber=1;tmi-sent-ts=1507246572675;turbo=1;user-id=1337;user-type= :onvar!onvar@onvar.tmi.twitch.tv PRIVMSG #onvar :Kappa Keepo Kappa";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchPrivmsg(privmsg) =>
+        {
+            assert_eq!(privmsg.id, "b34ccfc7-4977-403a-8a94-33c6bac34fb8");
+            assert_eq!(privmsg.user, "onvar");
+            assert_eq!(privmsg.name, "OnVar");
+            assert_eq!(privmsg.to, "#onvar");
+            assert_eq!(privmsg.message, "Kappa Keepo Kappa");
+            assert_eq!(privmsg.badges, vec![TwitchBadge { set: "broadcaster".into(), version: "1".into() },
+                                            TwitchBadge { set: "subscriber".into(), version: "6".into() }]);
+            assert_eq!(privmsg.emotes.len(), 2);
+            assert_eq!(privmsg.emotes[0].id, 25);
+            assert_eq!(privmsg.emotes[0].ranges, vec![(0, 4), (12, 16)]);
+            assert_eq!((privmsg.color.0, privmsg.color.1, privmsg.color.2), (0x0D, 0x42, 0x00));
+            assert_eq!(privmsg.bits, 0);
+            assert_eq!(privmsg.reply, None);
+        },
+        other => panic!("expected a PRIVMSG, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_reply()
+{
+    let raw = "@badges=;color=;display-name=Replier;emotes=;id=r1;reply-parent-display-name=OnVar;reply-parent-msg-body=hello\\sthere;reply-parent-msg-id=p1;reply-parent-user-id=1337;reply-parent-user-login=onvar;user-id=4 :replier!replier@replier.tmi.twitch.tv PRIVMSG #onvar :@OnVar hi";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchPrivmsg(privmsg) =>
+        {
+            assert_eq!(privmsg.reply, Some(TwitchReply {
+                parent_id: "p1".into(),
+                parent_user: "onvar".into(),
+                parent_name: "OnVar".into(),
+                parent_body: "hello there".into(),
+            }));
+        },
+        other => panic!("expected a PRIVMSG, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_cheer()
+{
+    let raw = "@badges=bits/100;bits=150;color=;display-name=Cheerer;emotes=;id=c1;user-id=3 :cheerer!cheerer@cheerer.tmi.twitch.tv PRIVMSG #onvar :Cheer100 cheer50 nice";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchPrivmsg(privmsg) => assert_eq!(privmsg.bits, 150),
+        other => panic!("expected a PRIVMSG, got {:?}", other),
+    }
+
+    assert_eq!(cheer_tier(1), 1);
+    assert_eq!(cheer_tier(99), 1);
+    assert_eq!(cheer_tier(100), 100);
+    assert_eq!(cheer_tier(4999), 1000);
+    assert_eq!(cheer_tier(5000), 5000);
+    assert_eq!(cheer_tier(250000), 10000);
+}
+
+#[test]
+fn test_parse_usernotice()
+{
+    let raw = "@badges=;color=#FF0000;display-name=Raider;emotes=;id=abc;login=raider;msg-id=raid;msg-param-viewerCount=5;room-id=1337;system-msg=5\\sraiders\\sfrom\\sRaider\\shave\\sjoined!;user-id=2 :tmi.twitch.tv USERNOTICE #onvar";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchUserNotice(notice) =>
+        {
+            assert_eq!(notice.channel, "#onvar");
+            assert_eq!(notice.msg_id, "raid");
+            assert_eq!(notice.system_msg, "5 raiders from Raider have joined!");
+            assert!(notice.privmsg.is_none());
+        },
+        other => panic!("expected a USERNOTICE, got {:?}", other),
+    }
+
+    let raw = "@badges=subscriber/12;color=;display-name=Sub;emotes=;id=def;login=sub;msg-id=resub;room-id=1337;system-msg=Sub\\ssubscribed\\sfor\\s12\\smonths.;user-id=3 :tmi.twitch.tv USERNOTICE #onvar :still here";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchUserNotice(notice) =>
+        {
+            let privmsg = notice.privmsg.unwrap();
+            assert_eq!(privmsg.user, "sub");
+            assert_eq!(privmsg.message, "still here");
+        },
+        other => panic!("expected a USERNOTICE, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_user_states()
+{
+    let raw = "@badge-info=;badges=moderator/1;color=;display-name=Some\\sName;emote-sets=0,33,50;mod=1;subscriber=0;user-type=mod :tmi.twitch.tv USERSTATE #onvar";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchUserState(channel, state) =>
+        {
+            assert_eq!(channel, "#onvar");
+            assert_eq!(state.display_name, "Some Name");
+            assert_eq!(state.emote_sets, vec![0, 33, 50]);
+            assert!(state.is_privileged());
+        },
+        other => panic!("expected a USERSTATE, got {:?}", other),
+    }
+
+    let raw = "@badge-info=;badges=;color=#0000FF;display-name=OnVar;emote-sets=0;user-id=42;user-type= :tmi.twitch.tv GLOBALUSERSTATE";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchGlobalUserState(state) =>
+        {
+            assert_eq!(state.user_id, 42);
+            assert_eq!((state.color.0, state.color.1, state.color.2), (0, 0, 0xFF));
+            assert!(state.badges.is_empty());
+            assert!(!state.is_privileged());
+        },
+        other => panic!("expected a GLOBALUSERSTATE, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_roomstate()
+{
+    let raw = "@emote-only=0;followers-only=-1;r9k=0;room-id=1337;slow=30;subs-only=1 :tmi.twitch.tv ROOMSTATE #onvar";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchRoomState(channel, state) =>
+        {
+            assert_eq!(channel, "#onvar");
+            assert_eq!(state.emote_only, Some(false));
+            assert_eq!(state.followers_only, Some(-1));
+            assert_eq!(state.slow, Some(30));
+            assert_eq!(state.subs_only, Some(true));
+            assert_eq!(state.room_id, 1337);
+        },
+        other => panic!("expected a ROOMSTATE, got {:?}", other),
+    }
+
+    /* Later updates only carry what changed */
+    match parse_raw("@room-id=1337;slow=0 :tmi.twitch.tv ROOMSTATE #onvar")
+    {
+        TwitchMessage::TwitchRoomState(_, state) =>
+        {
+            assert_eq!(state.slow, Some(0));
+            assert_eq!(state.subs_only, None);
+        },
+        other => panic!("expected a ROOMSTATE, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_moderation()
+{
+    let raw = "@ban-duration=600;ban-reason=Spamming\\sthe\\schat;room-id=1337;target-user-id=2;tmi-sent-ts=1 :tmi.twitch.tv CLEARCHAT #onvar :spammer";
+    match parse_raw(raw)
+    {
+        TwitchMessage::TwitchClearChat(clear) =>
+        {
+            assert_eq!(clear.target, Some("spammer".into()));
+            assert_eq!(clear.ban_duration, Some(600));
+            assert_eq!(clear.ban_reason, Some("Spamming the chat".into()));
+        },
+        other => panic!("expected a CLEARCHAT, got {:?}", other),
+    }
+
+    match parse_raw("@room-id=1337;tmi-sent-ts=1 :tmi.twitch.tv CLEARCHAT #onvar")
+    {
+        TwitchMessage::TwitchClearChat(clear) =>
+        {
+            assert_eq!(clear.target, None);
+            assert_eq!(clear.ban_reason, None);
+        },
+        other => panic!("expected a CLEARCHAT, got {:?}", other),
+    }
+
+    match parse_raw("@login=spammer;room-id=;target-msg-id=abc-123;tmi-sent-ts=1 :tmi.twitch.tv CLEARMSG #onvar :bad words")
+    {
+        TwitchMessage::TwitchClearMsg(clear) =>
+        {
+            assert_eq!(clear.login, "spammer");
+            assert_eq!(clear.target_msg_id, "abc-123");
+            assert_eq!(clear.message, "bad words");
+        },
+        other => panic!("expected a CLEARMSG, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_other_commands()
+{
+    match parse_raw("@msg-id=msg_slowmode :tmi.twitch.tv NOTICE #onvar :This room is in slow mode.")
+    {
+        TwitchMessage::TwitchNotice(notice) =>
+        {
+            assert_eq!(notice.msg_id, Some("msg_slowmode".into()));
+            assert_eq!(notice.message, "This room is in slow mode.");
+        },
+        other => panic!("expected a NOTICE, got {:?}", other),
+    }
+
+    match parse_raw(":tmi.twitch.tv HOSTTARGET #onvar :other 12")
+    {
+        TwitchMessage::TwitchHostTarget(host) =>
+        {
+            assert_eq!(host.target, Some("other".into()));
+            assert_eq!(host.viewers, Some(12));
+        },
+        other => panic!("expected a HOSTTARGET, got {:?}", other),
+    }
+
+    match parse_raw(":tmi.twitch.tv HOSTTARGET #onvar :- 0")
+    {
+        TwitchMessage::TwitchHostTarget(host) => assert_eq!(host.target, None),
+        other => panic!("expected a HOSTTARGET, got {:?}", other),
+    }
+
+    match parse_raw("@badges=;color=;display-name=Friend;emotes=;message-id=1;thread-id=1_2;turbo=0;user-id=2;user-type= :friend!friend@friend.tmi.twitch.tv WHISPER onvar :hi there")
+    {
+        TwitchMessage::TwitchWhisper(whisper) =>
+        {
+            assert_eq!(whisper.user, "friend");
+            assert_eq!(whisper.message, "hi there");
+        },
+        other => panic!("expected a WHISPER, got {:?}", other),
+    }
+
+    match parse_raw(":onvar!onvar@onvar.tmi.twitch.tv JOIN #onvar")
+    {
+        TwitchMessage::TwitchJoin(channel, user) => assert_eq!((channel.as_str(), user.as_str()), ("#onvar", "onvar")),
+        other => panic!("expected a JOIN, got {:?}", other),
+    }
+
+    match parse_raw(":onvar.tmi.twitch.tv 353 onvar = #onvar :onvar onvarbot")
+    {
+        TwitchMessage::TwitchNames(channel, names) =>
+        {
+            assert_eq!(channel, "#onvar");
+            assert_eq!(names, vec![String::from("onvar"), String::from("onvarbot")]);
+        },
+        other => panic!("expected a 353, got {:?}", other),
+    }
+
+    match parse_raw(":tmi.twitch.tv 001 onvar :Welcome, GLHF!")
+    {
+        TwitchMessage::TwitchNumeric(numeric, text) => assert_eq!((numeric, text.as_str()), (1, "Welcome, GLHF!")),
+        other => panic!("expected a numeric reply, got {:?}", other),
+    }
+
+    match parse_raw("PING :tmi.twitch.tv")
+    {
+        TwitchMessage::TwitchPing(value) => assert_eq!(value, "tmi.twitch.tv"),
+        other => panic!("expected a PING, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_emote_ranges_plain()
+{
+    let emote = TwitchEmoteRange { id: 25, ranges: vec![(0, 4), (12, 16)] };
+    assert_eq!(emote.char_ranges("Kappa Keepo Kappa"), vec![(0, 5), (12, 17)]);
+}
+
+#[test]
+fn test_emote_ranges_after_emoji()
+{
+    /* U+1F600 is one code point but two UTF-16 units */
+    let message = "\u{1F600} Kappa";
+    let code_points = TwitchEmoteRange { id: 25, ranges: vec![(2, 6)] };
+    assert_eq!(code_points.char_ranges(message), vec![(2, 7)]);
+    let utf16 = TwitchEmoteRange { id: 25, ranges: vec![(3, 7)] };
+    assert_eq!(utf16.char_ranges(message), vec![(2, 7)]);
+}
+
+#[test]
+fn test_emote_ranges_combining_and_zwj()
+{
+    /* "e" and a combining acute accent are two code points */
+    let message = "e\u{301} Kappa";
+    let emote = TwitchEmoteRange { id: 25, ranges: vec![(3, 7)] };
+    assert_eq!(emote.char_ranges(message), vec![(3, 8)]);
+
+    /* Man, ZWJ, woman, ZWJ, girl: five code points, eight UTF-16 units */
+    let message = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} Kappa \u{1F600} Keepo";
+    let emote = TwitchEmoteRange { id: 25, ranges: vec![(6, 10), (14, 18)] };
+    assert_eq!(emote.char_ranges(message), vec![(6, 11), (14, 19)]);
+    let utf16 = TwitchEmoteRange { id: 25, ranges: vec![(9, 13), (18, 22)] };
+    assert_eq!(utf16.char_ranges(message), vec![(6, 11), (14, 19)]);
+}
+
+#[test]
+fn test_emote_ranges_overflow()
+{
+    let emote = TwitchEmoteRange { id: 25, ranges: vec![(0, usize::max_value()), (0, 4)] };
+    assert_eq!(emote.char_ranges("Kappa"), vec![(0, 5)]);
+}
+
+#[test]
+fn test_emote_ranges_out_of_bounds()
+{
+    let emote = TwitchEmoteRange { id: 25, ranges: vec![(0, 40), (5, 2)] };
+    assert!(emote.char_ranges("Kappa").is_empty());
+}
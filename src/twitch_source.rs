@@ -0,0 +1,271 @@
+use image_source::ImageSource;
+use twitch_message::{TwitchBadge, TwitchEmote};
+use twitch_image_loader::{ImageError, ImageResult};
+use disk_cache::{DiskCache, SWEEP_GRACE_SECS};
+
+use twitch_api::TwitchApi;
+
+use hyper;
+use hyper::header::Headers;
+use hyper::client::Response;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper_rustls::TlsClient;
+
+use std::io::Read;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/* Animated, dark theme, 1x. Filled with the lowercase prefix and the tier. */
+const CHEERMOTE_URL: &'static str = "https://d3aqoihi2n8ty8.cloudfront.net/actions";
+/* Filled with the emote id, 1x */
+const EMOTE_URL: &'static str = "https://static-cdn.jtvnw.net/emoticons/v1";
+
+/* Badges and emotes from the Twitch API and CDN, kept on disk between runs */
+#[derive(Debug)]
+pub struct TwitchSource
+{
+    client_id: String,
+    disk_cache: Option<DiskCache>,
+}
+
+impl TwitchSource
+{
+    pub fn new(client_id: &str) -> TwitchSource
+    {
+        let disk_cache = DiskCache::from_env();
+        if let Some(ref disk_cache) = disk_cache
+        {
+            /* Nothing to do on failure, the cache may not exist yet */
+            let disk_cache = disk_cache.clone();
+            thread::spawn(move || disk_cache.sweep(Duration::from_secs(SWEEP_GRACE_SECS)));
+        }
+
+        TwitchSource
+        {
+            client_id: String::from(client_id),
+            disk_cache: disk_cache,
+        }
+    }
+
+    /* Made for each list, which are rare enough once cached */
+    fn api(&self) -> TwitchApi
+    {
+        TwitchApi::new(&self.client_id)
+    }
+}
+
+impl ImageSource for TwitchSource
+{
+    fn badge_sets(&self) -> ImageResult<Vec<(TwitchBadge, String)>>
+    {
+        let badge_list = try!(fetch_list(&self.disk_cache, "badges/global", || {
+            self.api()
+                .get_global_badges()
+                .map_err(|_| ImageError::Network(String::from("could not get the global badges")))
+                .map(|badge_sets| {
+                    let mut list = String::new();
+                    for (set, badge_set) in &badge_sets.badge_sets
+                    {
+                        for (version, badge_version) in &badge_set.versions
+                        {
+                            list.push_str(&format!("{}/{} {}\n", set, version, badge_version.image_url_1x));
+                        }
+                    }
+                    list
+                })
+        }));
+        Ok(parse_badge_list(&badge_list))
+    }
+
+    fn channel_badges(&self, channel: &str) -> ImageResult<Vec<(TwitchBadge, String)>>
+    {
+        let badge_list = try!(fetch_list(&self.disk_cache, &format!("badges/{}", channel), || {
+            let tapi = self.api();
+            let users = try!(tapi.get_user(channel)
+                                 .map_err(|_| ImageError::Network(format!("could not look up {}", channel))));
+            /* No such channel */
+            let user = try!(users.users.get(0).map(|user| user.id).ok_or(ImageError::NotFound));
+            tapi.get_subscriber_badges(user)
+                .map_err(|_| ImageError::Network(format!("could not get the badges of {}", channel)))
+                .map(|badge_sets| {
+                    let mut list = String::new();
+                    if let Some(subscriber_set) = badge_sets.badge_sets.get("subscriber")
+                    {
+                        for (version, badge_version) in &subscriber_set.versions
+                        {
+                            list.push_str(&format!("subscriber/{} {}\n", version, badge_version.image_url_1x));
+                        }
+                    }
+                    list
+                })
+        }));
+        Ok(parse_badge_list(&badge_list))
+    }
+
+    fn emote_set(&self, emote_set: u64) -> ImageResult<Vec<TwitchEmote>>
+    {
+        let emote_list = try!(fetch_list(&self.disk_cache, &format!("emote-sets/{}", emote_set), || {
+            self.api()
+                .get_emoticons(vec![emote_set])
+                .map_err(|_| ImageError::Network(format!("could not get emote set {}", emote_set)))
+                .map(|emoticon_set| {
+                    emoticon_set.emoticon_sets
+                                .values()
+                                .flat_map(|v| v.iter())
+                                .map(|e| format!("{} {}\n", e.id, e.code))
+                                .collect::<String>()
+                })
+        }));
+        Ok(parse_emote_list(&emote_list))
+    }
+
+    fn emote(&self, emote_id: u64) -> ImageResult<Vec<u8>>
+    {
+        fetch_cached(&self.disk_cache, &format!("{}/{}/1.0", EMOTE_URL, emote_id))
+    }
+
+    fn cheermote(&self, prefix: &str, tier: u64) -> ImageResult<Vec<u8>>
+    {
+        fetch_cached(&self.disk_cache, &format!("{}/{}/dark/animated/{}/1.gif", CHEERMOTE_URL, prefix, tier))
+    }
+
+    fn image(&self, url: &str) -> ImageResult<Vec<u8>>
+    {
+        fetch_cached(&self.disk_cache, url)
+    }
+}
+
+/* Serves an image from disk while it is fresh. Once stale the server is
+ * asked whether it changed, and if it cannot be reached the stale copy is
+ * still better than nothing.
+ */
+fn fetch_cached(disk_cache: &Option<DiskCache>, url: &str) -> ImageResult<Vec<u8>>
+{
+    let cached = disk_cache.as_ref().and_then(|cache| cache.get(url));
+    let mut headers = Headers::new();
+    if let Some(ref entry) = cached
+    {
+        if entry.fresh
+        {
+            return Ok(entry.data.clone());
+        }
+        if let Some(ref etag) = entry.etag
+        {
+            headers.set_raw("If-None-Match", vec![etag.clone().into_bytes()]);
+        }
+        if let Some(ref last_modified) = entry.last_modified
+        {
+            headers.set_raw("If-Modified-Since", vec![last_modified.clone().into_bytes()]);
+        }
+    }
+    let stale = |error: ImageError| cached.as_ref().map(|entry| entry.data.clone()).ok_or(error);
+
+    let client = hyper::Client::with_connector(HttpsConnector::new(TlsClient::new()));
+    let mut response = match client.get(url).headers(headers).send()
+    {
+        Ok(response) => response,
+        Err(error) => return stale(ImageError::Network(error.to_string())),
+    };
+    match response.status
+    {
+        StatusCode::NotModified =>
+        {
+            if let Some(ref cache) = *disk_cache
+            {
+                let _ = cache.refresh(url);
+            }
+            stale(ImageError::Network(String::from("not modified, but nothing was cached")))
+        },
+        StatusCode::Ok =>
+        {
+            let mut buffer = Vec::new();
+            if let Err(error) = response.read_to_end(&mut buffer)
+            {
+                return stale(ImageError::Network(error.to_string()));
+            }
+            if let Some(ref cache) = *disk_cache
+            {
+                let etag = header_value(&response, "ETag");
+                let last_modified = header_value(&response, "Last-Modified");
+                let _ = cache.put(url,
+                                  &buffer,
+                                  etag.as_ref().map(String::as_str),
+                                  last_modified.as_ref().map(String::as_str));
+            }
+            Ok(buffer)
+        },
+        StatusCode::NotFound | StatusCode::Gone => Err(ImageError::NotFound),
+        status => stale(ImageError::Network(status.to_string())),
+    }
+}
+
+/* Lists from the API have no validators, so they are only refetched once
+ * stale, and the stale copy is used if that fails.
+ */
+fn fetch_list<F>(disk_cache: &Option<DiskCache>, key: &str, fetch: F) -> ImageResult<String>
+    where F: FnOnce() -> ImageResult<String>
+{
+    let cached = disk_cache.as_ref()
+                           .and_then(|cache| cache.get(key))
+                           .and_then(|entry| String::from_utf8(entry.data.clone()).ok()
+                                                                                  .map(|list| (list, entry.fresh)));
+    match cached
+    {
+        Some((list, true)) => Ok(list),
+        stale =>
+        {
+            match fetch()
+            {
+                Ok(list) =>
+                {
+                    if let Some(ref cache) = *disk_cache
+                    {
+                        let _ = cache.put(key, list.as_bytes(), None, None);
+                    }
+                    Ok(list)
+                },
+                Err(error) => stale.map(|(list, _)| list).ok_or(error),
+            }
+        },
+    }
+}
+
+fn header_value(response: &Response, name: &str) -> Option<String>
+{
+    response.headers
+            .get_raw(name)
+            .and_then(|values| values.get(0))
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+/* "set/version url" per line */
+fn parse_badge_list(list: &str) -> Vec<(TwitchBadge, String)>
+{
+    list.lines()
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ' ');
+            match (split.next().map(TwitchBadge::from_str), split.next())
+            {
+                (Some(Ok(badge)), Some(url)) => Some((badge, String::from(url))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/* "id code" per line */
+fn parse_emote_list(list: &str) -> Vec<TwitchEmote>
+{
+    list.lines()
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ' ');
+            match (split.next().map(u64::from_str), split.next())
+            {
+                (Some(Ok(id)), Some(code)) => Some(TwitchEmote { id: id, code: String::from(code) }),
+                _ => None,
+            }
+        })
+        .collect()
+}
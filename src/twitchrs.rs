@@ -1,7 +1,12 @@
-use twitch_message::{TwitchMessage, TwitchPrivmsg, TwitchUserState, UserColor};
+use twitch_message::{normalize_channel, TwitchMessage, TwitchPrivmsg, TwitchReply, TwitchUserState, TwitchRoomState, UserColor};
 use twitch_image_loader::TwitchImageLoader;
+use credentials::Credentials;
+use session::Session;
+use send_queue::{SendQueue, Outgoing};
+use chat_error::{ChatError, ChatResult};
+use token_validator::{TokenValidator, TokenInfo, ValidationError};
 
-use ui::main_window::MainWindow;
+use ui::main_window::{self, LoginDone, MainWindow, STATUS_TAB};
 
 use twitch_chat::client::{ChatClient, ChatSender, TwitchReceiver, TwitchSender, ClientError, ClientResult};
 use twitch_chat::message::Message;
@@ -12,280 +17,1115 @@ use gtk;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::thread;
-use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use std::cmp;
 
 const CLIENT_ID: &'static str = "8t59b442f4twnhw5ur8vw7vf5muoauf";
+/* Whispers are sent as a /w command to this channel */
+const WHISPER_CHANNEL: &'static str = "#jtv";
+const RECONNECT_BASE_DELAY_MS: u64 = 1000;
+const RECONNECT_MAX_DELAY_MS: u64 = 60000;
+/* A connection has to last this long before the backoff starts over */
+const STABLE_CONNECTION_SECS: u64 = 60;
 
 pub struct Client
 {
     sender: ChatSender,
     receiver: Receiver<ClientResult<Message>>,
+    queue: SendQueue,
 }
 
-pub struct TwitchRS
+impl Client
 {
-    window: Rc<RefCell<MainWindow>>,
-    client: Rc<RefCell<Option<Client>>>,
-    twitch_loader: Rc<RefCell<TwitchImageLoader>>,
-    global_user_state: Rc<RefCell<TwitchUserState>>,
-    channel_user_state: Rc<RefCell<HashMap<String, TwitchUserState>>>,
+    fn queue_message(&mut self, channel: &str, text: &str)
+    {
+        self.queue.push(Outgoing::Message(channel.into(), text.into()));
+    }
+
+    fn queue_reply(&mut self, channel: &str, parent_id: &str, text: &str)
+    {
+        self.queue.push(Outgoing::Reply(channel.into(), parent_id.into(), text.into()));
+    }
+
+    fn queue_join(&mut self, channel: &str)
+    {
+        self.queue.push(Outgoing::Join(channel.into()));
+    }
+
+    /* Sends whatever the rate limits allow right now, noting when each
+     * channel was last written to for slow mode. Stops at the first failure
+     * and hands back what could not be sent.
+     */
+    fn flush(&mut self,
+             channel_user_state: &HashMap<String, TwitchUserState>,
+             last_sent: &mut HashMap<String, Instant>) -> Result<(), (Outgoing, ChatError)>
+    {
+        let is_privileged = |channel: &str| channel_user_state.get(channel)
+                                                              .map(TwitchUserState::is_privileged)
+                                                              .unwrap_or(false);
+        while let Some(outgoing) = self.queue.pop_ready(&is_privileged)
+        {
+            let result = match outgoing
+            {
+                Outgoing::Message(ref channel, ref text) => self.sender.send_message(channel, text),
+                /* Replies need a client tag, which send_message has no way to add */
+                Outgoing::Reply(ref channel, ref parent_id, ref text) =>
+                    self.sender.send_raw(&format!("@reply-parent-msg-id={} PRIVMSG {} :{}", parent_id, channel, text)),
+                Outgoing::Join(ref channel) => self.sender.send_join(channel),
+            };
+            if let Err(e) = result
+            {
+                return Err((outgoing, ChatError::Send(e)));
+            }
+            match outgoing
+            {
+                Outgoing::Message(channel, _) | Outgoing::Reply(channel, _, _) =>
+                {
+                    last_sent.insert(channel, Instant::now());
+                },
+                Outgoing::Join(_) => {},
+            }
+        }
+        Ok(())
+    }
 }
 
-impl TwitchRS
+/* A logged in user, with its own connection and user state */
+struct Account
 {
-    pub fn new() -> TwitchRS
+    credentials: Option<Credentials>,
+    /* What the token was validated as, None for anonymous logins */
+    token_info: Option<TokenInfo>,
+    /* None while disconnected */
+    client: Option<Client>,
+    global_user_state: TwitchUserState,
+    channel_user_state: HashMap<String, TwitchUserState>,
+    reconnect_attempt: u32,
+    /* Set while disconnected and waiting to retry */
+    retry_at: Option<Instant>,
+    /* When the current connection was made */
+    connected_at: Option<Instant>,
+    /* A reconnect running on another thread */
+    connecting: Option<Receiver<ChatResult<Client>>>,
+}
+
+impl Account
+{
+    fn name(&self) -> String
     {
-        gtk::init().expect("Could not init gtk");
+        account_name(&self.credentials)
+    }
+}
 
-        let init_user_state = TwitchUserState {
-            badges: Vec::new(),
-            color: UserColor(0, 0, 0),
-            display_name: String::new(),
-            emote_sets: Vec::new(),
-            user_id: 0,
-            user_type: (),
-        };
+fn account_name(credentials: &Option<Credentials>) -> String
+{
+    credentials.as_ref()
+               .map(|credentials| credentials.username.to_lowercase())
+               .unwrap_or(String::from("anonymous"))
+}
 
-        let mut trs = TwitchRS
+fn blank_user_state() -> TwitchUserState
+{
+    TwitchUserState {
+        badges: Vec::new(),
+        color: UserColor(0, 0, 0),
+        display_name: String::new(),
+        emote_sets: Vec::new(),
+        user_id: 0,
+        user_type: (),
+    }
+}
+
+fn connect(credentials: &Option<Credentials>) -> ChatResult<Client>
+{
+    let mut chat_client = try!(ChatClient::connect().map_err(ChatError::Connect));
+    try!(chat_client.send_authenticate(credentials.as_ref().map(Credentials::to_auth))
+                    .map_err(ChatError::Connect));
+    let (sender, mut receiver) = chat_client.split();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        loop
         {
-            window: Rc::new(RefCell::new(MainWindow::new())),
-            client: Rc::new(RefCell::new(None)),
-            twitch_loader: Rc::new(RefCell::new(TwitchImageLoader::new(CLIENT_ID))),
-            global_user_state: Rc::new(RefCell::new(init_user_state)),
-            channel_user_state: Rc::new(RefCell::new(HashMap::new())),
-        };
+            let result = receiver.get_message();
+            let disconnected = match result
+            {
+                Err(ClientError::WebSocketError(_)) => true,
+                _ => false,
+            };
+            /* The receiving end goes away when we reconnect */
+            if tx.send(result).is_err() || disconnected
+            {
+                return;
+            }
+        }
+    });
 
-        trs.setup_callbacks();
+    Ok(Client {
+        sender: sender,
+        receiver: rx,
+        queue: SendQueue::new(),
+    })
+}
 
-        return trs;
-    }
+/* Runs connect on another thread, so a slow server does not freeze the
+ * window. The result arrives on the returned channel.
+ */
+fn connect_in_background(credentials: &Option<Credentials>) -> Receiver<ChatResult<Client>>
+{
+    let (tx, rx) = mpsc::channel();
+    let credentials = credentials.clone();
+    thread::spawn(move || {
+        /* The account may have been replaced by then */
+        let _ = tx.send(connect(&credentials));
+    });
+    rx
+}
 
-    fn setup_callbacks(&mut self)
+/* Capped exponential backoff, half of it random so clients don't retry in lockstep */
+fn reconnect_delay(attempt: u32) -> Duration
+{
+    let exponential = RECONNECT_BASE_DELAY_MS.saturating_mul(1 << cmp::min(attempt, 16));
+    let capped = cmp::min(exponential, RECONNECT_MAX_DELAY_MS);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let jitter = hasher.finish() % (capped / 2 + 1);
+    Duration::from_millis(capped / 2 + jitter)
+}
+
+#[derive(Clone)]
+struct Env
+{
+    window_clone: Rc<RefCell<MainWindow>>,
+    accounts_clone: Rc<RefCell<Vec<Account>>>,
+    twitch_loader_clone: Rc<RefCell<TwitchImageLoader>>,
+    channel_room_state_clone: Rc<RefCell<HashMap<String, TwitchRoomState>>>,
+    last_sent_clone: Rc<RefCell<HashMap<String, Instant>>>,
+}
+
+/* login is the account's, display names can differ from it by more than case */
+fn echo_message(tab_name: &str, text: &str, login: &str, user_state: &TwitchUserState, reply: Option<TwitchReply>) -> TwitchMessage
+{
+    TwitchMessage::TwitchEcho (
+        TwitchPrivmsg {
+            id: String::new(),
+            user: login.into(),
+            name: user_state.display_name.clone(),
+            emotes: Vec::new(),
+            badges: user_state.badges.clone(),
+            color: user_state.color,
+            to: tab_name.into(),
+            message: text.into(),
+            bits: 0,
+            reply: reply,
+        },
+        user_state.emote_sets.clone()
+    )
+}
+
+/* Tells the user about a message that never went out, in the tab it was
+ * typed in, so it can be retried.
+ */
+fn report_unsent(window: &mut MainWindow, outgoing: Outgoing, error: &ChatError)
+{
+    match outgoing
     {
+        Outgoing::Message(channel, text) | Outgoing::Reply(channel, _, text) =>
         {
-            struct Env
+            window.status_log_line(&format!("Message to {} not sent: {}", channel, error));
+            if channel == WHISPER_CHANNEL
             {
-                window_clone: Rc<RefCell<MainWindow>>,
-                client_clone: Rc<RefCell<Option<Client>>>,
-                twitch_loader_clone: Rc<RefCell<TwitchImageLoader>>,
-                global_user_state_clone: Rc<RefCell<TwitchUserState>>,
-                channel_user_state_clone: Rc<RefCell<HashMap<String, TwitchUserState>>>,
+                /* Whispers are queued as "/w user text" */
+                let mut split = text.splitn(3, ' ').skip(1);
+                if let (Some(user), Some(whisper)) = (split.next(), split.next())
+                {
+                    window.channel_print_unsent(user.into(), whisper, &error.to_string());
+                }
             }
-            let env = Env
+            else
             {
-                window_clone: self.window.clone(),
-                client_clone: self.client.clone(),
-                twitch_loader_clone: self.twitch_loader.clone(),
-                global_user_state_clone: self.global_user_state.clone(),
-                channel_user_state_clone: self.channel_user_state.clone(),
-            };
+                window.channel_print_unsent(channel, &text, &error.to_string());
+            }
+        },
+        Outgoing::Join(channel) =>
+        {
+            window.status_log_line(&format!("Could not join {}: {}", channel, error));
+        },
+    }
+}
+
+/* Closes a tab and forgets everything kept for it, leaving the channel if
+ * it is not a whisper tab.
+ */
+fn part_channel(name: &str,
+                window: &mut MainWindow,
+                accounts: &mut Vec<Account>,
+                channel_room_state: &mut HashMap<String, TwitchRoomState>,
+                last_sent: &mut HashMap<String, Instant>)
+{
+    if !window.remove_channel(name)
+    {
+        return;
+    }
+    channel_room_state.remove(name);
+    last_sent.remove(name);
 
-            fn on_text_callback(tab_name: String, text: String, env: &mut Env)
+    for account in accounts.iter_mut()
+    {
+        account.channel_user_state.remove(name);
+        if is_whisper_tab(name)
+        {
+            continue;
+        }
+        if let Some(ref mut twitch_client) = account.client
+        {
+            twitch_client.queue.forget_channel(name);
+            if let Err(e) = twitch_client.sender.send_raw(&format!("PART {}", name))
             {
-                let mut window = env.window_clone.borrow_mut();
-                let mut client = env.client_clone.borrow_mut();
-                let mut twitch_loader = env.twitch_loader_clone.borrow_mut();
-                let global_user_state = env.global_user_state_clone.borrow_mut();
-                let channel_user_state = env.channel_user_state_clone.borrow_mut();
+                window.status_log_line(&format!("Could not leave {}: {}", name, ChatError::Send(e)));
+            }
+        }
+    }
+}
+
+/* A login whose token check and connection run on another thread */
+struct PendingLogin
+{
+    credentials: Option<Credentials>,
+    receiver: Receiver<Result<Connected, String>>,
+    /* Told how it went */
+    done: LoginDone,
+}
 
-                if text.starts_with("/")
+/* What a login thread hands back */
+struct Connected
+{
+    client: Client,
+    token_info: Option<TokenInfo>,
+    /* For the status tab */
+    warning: Option<String>,
+}
+
+/* Starts checking the token and connecting, finish_login takes it from
+ * there once the thread is done.
+ */
+fn login(credentials: Option<Credentials>,
+         window: &mut MainWindow,
+         pending_logins: &mut Vec<PendingLogin>,
+         validator: &Arc<TokenValidator>,
+         done: LoginDone)
+{
+    window.status_log_line(&format!("Logging in as {}", account_name(&credentials)));
+    let (tx, rx) = mpsc::channel();
+    let credentials_clone = credentials.clone();
+    let validator_clone = validator.clone();
+    thread::spawn(move || {
+        let _ = tx.send(check_and_connect(&credentials_clone, &validator_clone));
+    });
+    pending_logins.push(PendingLogin {
+        credentials: credentials,
+        receiver: rx,
+        done: done,
+    });
+}
+
+/* Checks the token and connects. Errors are ready for the status tab. */
+fn check_and_connect(credentials: &Option<Credentials>, validator: &TokenValidator) -> Result<Connected, String>
+{
+    let mut token_info = None;
+    let mut warning = None;
+    if let Some(ref credentials) = *credentials
+    {
+        match validator.validate(&credentials.oauth)
+        {
+            Ok(info) =>
+            {
+                if info.login != credentials.username.to_lowercase()
+                {
+                    return Err(format!("Login failed: the token belongs to {}, not {}", info.login, credentials.username));
+                }
+                token_info = Some(info);
+            },
+            Err(e @ ValidationError::Rejected) => return Err(format!("Login failed: {}", e)),
+            /* Chat will still refuse a bad token with a NOTICE */
+            Err(e) => warning = Some(format!("Could not validate the token, logging in anyway: {}", e)),
+        }
+    }
+
+    connect(credentials).map(|client| Connected {
+                            client: client,
+                            token_info: token_info,
+                            warning: warning,
+                        })
+                        .map_err(|e| format!("Login failed: {}", e))
+}
+
+/* Joins every open channel with the new connection. Logging in to an
+ * account that is already logged in replaces its connection.
+ */
+fn finish_login(pending: PendingLogin,
+                result: Result<Connected, String>,
+                window: &mut MainWindow,
+                accounts: &mut Vec<Account>)
+{
+    let connected = match result
+    {
+        Ok(connected) => connected,
+        Err(error) =>
+        {
+            window.status_log_line(&error);
+            (pending.done)(Err(error));
+            return;
+        },
+    };
+    if let Some(ref warning) = connected.warning
+    {
+        window.status_log_line(warning);
+    }
+
+    let mut new_client = connected.client;
+    for channel in window.channel_names()
+    {
+        new_client.queue_join(&channel);
+    }
+    let account = Account {
+        credentials: pending.credentials,
+        token_info: connected.token_info,
+        client: Some(new_client),
+        global_user_state: blank_user_state(),
+        channel_user_state: HashMap::new(),
+        reconnect_attempt: 0,
+        retry_at: None,
+        connected_at: Some(Instant::now()),
+        connecting: None,
+    };
+    let name = account.name();
+    match accounts.iter().position(|a| a.name() == name)
+    {
+        Some(i) => accounts[i] = account,
+        None => accounts.push(account),
+    }
+    show_accounts(window, accounts);
+    (pending.done)(Ok(()));
+}
+
+/* Updates the per-tab account pickers and the window title */
+fn save_session(window: &MainWindow, accounts: &[Account])
+{
+    let session = Session
+    {
+        logins: accounts.iter()
+                        .map(|account| account.credentials
+                                              .as_ref()
+                                              .map(|credentials| credentials.username.clone()))
+                        .collect(),
+        channels: window.channel_names(),
+    };
+    if let Err(e) = session.save()
+    {
+        println!("Error: Could not save session: {}", e);
+    }
+}
+
+fn show_accounts(window: &mut MainWindow, accounts: &[Account])
+{
+    window.set_identities(accounts.iter().map(Account::name).collect());
+    let logged_in: Vec<String> = accounts.iter()
+                                         .map(|account| match account.token_info
+                                         {
+                                             Some(ref info) if !info.scopes.is_empty() =>
+                                                 format!("{} ({})", info.login, info.scopes.join(" ")),
+                                             _ => account.name(),
+                                         })
+                                         .collect();
+    window.set_logged_in(&logged_in);
+}
+
+/* The account chosen in the tab, or the first connected one for tabs
+ * without a choice.
+ */
+fn sending_account<'a>(accounts: &'a mut Vec<Account>, window: &MainWindow, tab_name: &str) -> Option<&'a mut Account>
+{
+    let position = match window.channel_identity(tab_name)
+    {
+        Some(identity) => accounts.iter().position(|a| a.name() == identity),
+        None => accounts.iter().position(|a| a.client.is_some()),
+    };
+    position.map(move |i| &mut accounts[i])
+}
+
+/* What Twitch says before dropping a connection with a bad token */
+fn print_usage(window: &mut MainWindow, tab_name: String, usage: &str)
+{
+    if tab_name == STATUS_TAB
+    {
+        window.status_log_line(usage);
+    }
+    else
+    {
+        window.channel_print_line(tab_name, usage);
+    }
+}
+
+fn is_auth_failure(notice: &str) -> bool
+{
+    notice.starts_with("Login authentication failed") || notice.starts_with("Improperly formatted auth")
+}
+
+/* Whisper tabs are named after the other user and have no leading '#' */
+fn is_whisper_tab(tab_name: &str) -> bool
+{
+    !tab_name.starts_with("#") && tab_name != STATUS_TAB
+}
+
+fn on_text_callback(tab_name: String, text: String, env: &mut Env)
+{
+    let mut window = env.window_clone.borrow_mut();
+    let mut accounts = env.accounts_clone.borrow_mut();
+    let mut twitch_loader = env.twitch_loader_clone.borrow_mut();
+    let mut channel_room_state = env.channel_room_state_clone.borrow_mut();
+    let mut last_sent = env.last_sent_clone.borrow_mut();
+
+    if text.starts_with("/")
+    {
+        let args: Vec<_> = text[1..].split(' ').collect();
+        if let Some(cmd) = args.get(0)
+        {
+            match *cmd
+            {
+                "join" =>
                 {
-                    let args: Vec<_> = text[1..].split(' ').collect();
-                    if let Some(cmd) = args.get(0)
+                    if accounts.iter().any(|a| a.client.is_some())
                     {
-                        match *cmd
+                        /* Accepts "/join a b" as well as "/join a,b" */
+                        let channels = args[1..].iter()
+                                                .flat_map(|arg| arg.split(','))
+                                                .filter(|channel| !channel.trim().trim_left_matches('#').is_empty())
+                                                .map(normalize_channel);
+                        for channel in channels
                         {
-                            "join" =>
+                            if window.has_channel(&channel)
                             {
-                                if let Some(ref mut twitch_client) = *client
+                                window.focus_channel(&channel);
+                            }
+                            else
+                            {
+                                window.add_channel(channel.clone(), on_text_callback, env.clone());
+                                for twitch_client in accounts.iter_mut().filter_map(|a| a.client.as_mut())
                                 {
-                                    if let Some(channel) = args.get(1)
-                                    {
-                                        let inner_env = Env
-                                        {
-                                            window_clone: env.window_clone.clone(),
-                                            client_clone: env.client_clone.clone(),
-                                            twitch_loader_clone: env.twitch_loader_clone.clone(),
-                                            global_user_state_clone: env.global_user_state_clone.clone(),
-                                            channel_user_state_clone: env.channel_user_state_clone.clone(),
-                                        };
-                                        window.add_channel(String::from(*channel), on_text_callback, inner_env);
-                                        twitch_client.sender.send_join(channel).unwrap();
-                                    }
+                                    twitch_client.queue_join(&channel);
                                 }
-                            },
-                            "ban" | "timeout" | "color" | "host" | "unban" =>
+                            }
+                        }
+                    }
+                    else
+                    {
+                        window.status_log_line("Not connected, log in before joining a channel");
+                    }
+                },
+                "part" =>
+                {
+                    let channel = match args.get(1)
+                    {
+                        /* Whisper tabs are named after the user, without a '#' */
+                        Some(name) if is_whisper_tab(&name.to_lowercase()) && window.has_channel(&name.to_lowercase()) =>
+                            name.to_lowercase(),
+                        Some(name) => normalize_channel(name),
+                        None => tab_name,
+                    };
+                    part_channel(&channel,
+                                 &mut window,
+                                 &mut accounts,
+                                 &mut channel_room_state,
+                                 &mut last_sent);
+                },
+                "w" =>
+                {
+                    if args.len() < 3 || args[2..].join(" ").trim().is_empty()
+                    {
+                        print_usage(&mut window, tab_name, "Usage: /w user message");
+                        return;
+                    }
+                    match sending_account(&mut accounts, &window, &tab_name)
+                    {
+                        Some(&mut Account { client: Some(ref mut twitch_client), ref global_user_state, ref credentials, .. }) =>
+                        {
+                            if let Some(user) = args.get(1)
                             {
-                                if let Some(ref mut twitch_client) = *client
+                                let user = user.to_lowercase();
+                                let whisper = args[2..].join(" ");
+                                if !window.has_channel(&user)
                                 {
-                                    twitch_client.sender.send_message(&tab_name, &text).unwrap();
-                                    let user_state = channel_user_state.get(&tab_name)
-                                                                       .unwrap_or(&global_user_state);
-                                    let echo_message = TwitchMessage::TwitchEcho (
-                                        TwitchPrivmsg {
-                                            name: user_state.display_name.clone(),
-                                            emotes: Vec::new(),
-                                            badges: user_state.badges.clone(),
-                                            color: user_state.color,
-                                            to: tab_name.clone(),
-                                            message: text.clone(),
-                                        },
-                                        user_state.emote_sets.clone()
-                                    );
-                                    window.channel_print_message(tab_name, echo_message, &mut twitch_loader);
+                                    window.add_channel(user.clone(), on_text_callback, env.clone());
+                                    window.channel_set_identity(&user, &account_name(credentials));
                                 }
+                                twitch_client.queue_message(WHISPER_CHANNEL, &format!("/w {} {}", user, whisper));
+                                let echo = echo_message(&user, &whisper, &account_name(credentials), global_user_state, None);
+                                window.channel_print_message(user, echo, &mut twitch_loader);
                             }
-                            _ => {},
-                        }
+                        },
+                        _ =>
+                        {
+                            window.channel_print_unsent(tab_name, &text, &ChatError::NotConnected.to_string());
+                        },
+                    }
+                },
+                "reply" =>
+                {
+                    /* "/reply user text" answers that user's latest message in this tab */
+                    if args.len() < 3 || args[2..].join(" ").trim().is_empty()
+                    {
+                        print_usage(&mut window, tab_name, "Usage: /reply user message");
+                        return;
+                    }
+                    let user = args.get(1)
+                                   .map(|user| user.trim_left_matches('@').to_lowercase())
+                                   .unwrap_or(String::new());
+                    let reply_text = args.iter()
+                                         .skip(2)
+                                         .cloned()
+                                         .collect::<Vec<_>>()
+                                         .join(" ");
+                    let target = match window.channel_reply_target(&tab_name, &user)
+                    {
+                        Some(target) => target,
+                        None =>
+                        {
+                            window.channel_print_line(tab_name, &format!("No message from {} to reply to", user));
+                            return;
+                        },
+                    };
+                    match sending_account(&mut accounts, &window, &tab_name)
+                    {
+                        Some(&mut Account { client: Some(ref mut twitch_client), ref global_user_state, ref channel_user_state, ref credentials, .. }) =>
+                        {
+                            twitch_client.queue_reply(&tab_name, &target.parent_id, &reply_text);
+                            let user_state = channel_user_state.get(&tab_name)
+                                                               .unwrap_or(global_user_state);
+                            let echo = echo_message(&tab_name, &reply_text, &account_name(credentials), user_state, Some(target));
+                            window.channel_print_message(tab_name, echo, &mut twitch_loader);
+                        },
+                        _ =>
+                        {
+                            window.channel_print_unsent(tab_name, &reply_text, &ChatError::NotConnected.to_string());
+                        },
+                    }
+                },
+                "cache" =>
+                {
+                    for (name, stats) in twitch_loader.cache_stats()
+                    {
+                        window.status_log_line(&format!("Image cache, {}: {}", name, stats));
+                    }
+                    let pixbuf_stats = window.pixbuf_stats();
+                    window.status_log_line(&format!("Image cache, decoded: {}", pixbuf_stats));
+                },
+                "ban" | "timeout" | "color" | "host" | "unban" =>
+                {
+                    match sending_account(&mut accounts, &window, &tab_name)
+                    {
+                        Some(&mut Account { client: Some(ref mut twitch_client), ref global_user_state, ref channel_user_state, ref credentials, .. }) =>
+                        {
+                            twitch_client.queue_message(&tab_name, &text);
+                            let user_state = channel_user_state.get(&tab_name)
+                                                               .unwrap_or(global_user_state);
+                            let echo = echo_message(&tab_name, &text, &account_name(credentials), user_state, None);
+                            window.channel_print_message(tab_name, echo, &mut twitch_loader);
+                        },
+                        _ =>
+                        {
+                            window.channel_print_unsent(tab_name, &text, &ChatError::NotConnected.to_string());
+                        },
                     }
                 }
-                else if &tab_name != "status"
+                _ => {},
+            }
+        }
+    }
+    else if is_whisper_tab(&tab_name)
+    {
+        match sending_account(&mut accounts, &window, &tab_name)
+        {
+            Some(&mut Account { client: Some(ref mut twitch_client), ref global_user_state, ref credentials, .. }) =>
+            {
+                twitch_client.queue_message(WHISPER_CHANNEL, &format!("/w {} {}", tab_name, text));
+                let echo = echo_message(&tab_name, &text, &account_name(credentials), global_user_state, None);
+                window.channel_print_message(tab_name, echo, &mut twitch_loader);
+            },
+            _ =>
+            {
+                window.channel_print_unsent(tab_name, &text, &ChatError::NotConnected.to_string());
+            },
+        }
+    }
+    else if &tab_name != STATUS_TAB
+    {
+        if let Some(&mut Account { client: Some(ref mut twitch_client), ref global_user_state, ref channel_user_state, ref credentials, .. }) = sending_account(&mut accounts, &window, &tab_name)
+        {
+            let user_state = channel_user_state.get(&tab_name)
+                                               .unwrap_or(global_user_state);
+            let slow = channel_room_state.get(&tab_name)
+                                         .and_then(|r| r.slow)
+                                         .unwrap_or(0);
+            if slow > 0 && !user_state.is_privileged()
+            {
+                /* last_sent only moves once a message leaves the queue */
+                if twitch_client.queue.queued_for(&tab_name) > 0
+                {
+                    window.channel_print_unsent(tab_name.clone(), &text,
+                                                "slow mode, the last message is still waiting to be sent");
+                    return;
+                }
+                if let Some(last) = last_sent.get(&tab_name)
                 {
-                    if let Some(ref mut twitch_client) = *client
+                    let elapsed = last.elapsed().as_secs();
+                    if elapsed < slow
                     {
-                        twitch_client.sender.send_message(&tab_name, &text).unwrap();
-                        let user_state = channel_user_state.get(&tab_name)
-                                                           .unwrap_or(&global_user_state);
-                        let echo_message = TwitchMessage::TwitchEcho (
-                            TwitchPrivmsg {
-                                name: user_state.display_name.clone(),
-                                emotes: Vec::new(),
-                                badges: user_state.badges.clone(),
-                                color: user_state.color,
-                                to: tab_name.clone(),
-                                message: text.clone(),
-                            },
-                            user_state.emote_sets.clone()
-                        );
-                        window.channel_print_message(tab_name, echo_message, &mut twitch_loader);
+                        window.channel_print_unsent(tab_name.clone(), &text,
+                                                    &format!("slow mode, wait {} s", slow - elapsed));
+                        return;
                     }
                 }
-            };
-            self.window.borrow_mut().on_status_text(on_text_callback, env);
+            }
+
+            twitch_client.queue_message(&tab_name, &text);
+            let echo = echo_message(&tab_name, &text, &account_name(credentials), user_state, None);
+            window.channel_print_message(tab_name, echo, &mut twitch_loader);
+        }
+        else
+        {
+            window.channel_print_unsent(tab_name, &text, &ChatError::NotConnected.to_string());
+        }
+    }
+}
+
+pub struct TwitchRS
+{
+    window: Rc<RefCell<MainWindow>>,
+    /* The first connected account is the one whose channel messages are shown */
+    accounts: Rc<RefCell<Vec<Account>>>,
+    twitch_loader: Rc<RefCell<TwitchImageLoader>>,
+    channel_room_state: Rc<RefCell<HashMap<String, TwitchRoomState>>>,
+    last_sent: Rc<RefCell<HashMap<String, Instant>>>,
+    validator: Arc<TokenValidator>,
+    pending_logins: Rc<RefCell<Vec<PendingLogin>>>,
+}
+
+impl TwitchRS
+{
+    pub fn new() -> TwitchRS
+    {
+        gtk::init().expect("Could not init gtk");
+
+        let mut trs = TwitchRS
+        {
+            window: Rc::new(RefCell::new(MainWindow::new())),
+            accounts: Rc::new(RefCell::new(Vec::new())),
+            twitch_loader: Rc::new(RefCell::new(TwitchImageLoader::new(CLIENT_ID))),
+            channel_room_state: Rc::new(RefCell::new(HashMap::new())),
+            last_sent: Rc::new(RefCell::new(HashMap::new())),
+            validator: Arc::new(TokenValidator::from_env()),
+            pending_logins: Rc::new(RefCell::new(Vec::new())),
+        };
+
+        trs.setup_callbacks();
+        trs.restore_session();
+
+        return trs;
+    }
+
+    fn env(&self) -> Env
+    {
+        Env
+        {
+            window_clone: self.window.clone(),
+            accounts_clone: self.accounts.clone(),
+            twitch_loader_clone: self.twitch_loader.clone(),
+            channel_room_state_clone: self.channel_room_state.clone(),
+            last_sent_clone: self.last_sent.clone(),
         }
+    }
 
+    /* Reopens the tabs from last time and logs back in */
+    fn restore_session(&mut self)
+    {
+        let session = Session::load();
+        if session.logins.iter().any(|username| username.is_some())
+        {
+            /* The dialog's main loop runs the timeout that borrows the window */
+            let accounts = self.window.borrow().saved_accounts();
+            if main_window::unlock_accounts(&accounts)
+            {
+                self.window.borrow().refill_account_menus();
+            }
+        }
+        let mut window = self.window.borrow_mut();
+        for channel in session.channels
         {
-            let client_clone = self.client.clone();
-            self.window.borrow_mut().on_login(move |auth| {
-                let mut client = client_clone.borrow_mut();
-                let mut chat_client = ChatClient::connect().unwrap();
-                chat_client.send_authenticate(auth).unwrap();
-                let (sender, mut receiver) = chat_client.split();
-                let (tx, rx) = mpsc::channel();
-    
-                thread::spawn(move || {
-                    loop
+            window.add_channel(normalize_channel(&channel), on_text_callback, self.env());
+        }
+        for username in session.logins
+        {
+            let credentials = match username
+            {
+                Some(username) => match window.saved_account(&username)
+                {
+                    Some(account) => Some(account),
+                    None =>
                     {
-                        let result = receiver.get_message();
-                        if let Err(ClientError::WebSocketError(_)) = result
-                        {
-                            tx.send(result).unwrap();
-                            return;
-                        }
-                        tx.send(result).unwrap();
-                    }
-                });
-                *client = Some(Client {
-                    sender: sender,
-                    receiver: rx,
-                });
+                        window.status_log_line(&format!("No saved account for {}, not logging in", username));
+                        continue;
+                    },
+                },
+                None => None,
+            };
+            /* Failures are logged to the status tab */
+            login(credentials,
+                  &mut window,
+                  &mut self.pending_logins.borrow_mut(),
+                  &self.validator,
+                  Box::new(|_: Result<(), String>| {}));
+        }
+    }
+
+    fn setup_callbacks(&mut self)
+    {
+        let env = self.env();
+
+        self.window.borrow_mut().on_status_text(on_text_callback, env.clone());
+
+        {
+            let env_clone = env.clone();
+            self.window.borrow_mut().on_close_channel(move |name| {
+                part_channel(&name,
+                             &mut env_clone.window_clone.borrow_mut(),
+                             &mut env_clone.accounts_clone.borrow_mut(),
+                             &mut env_clone.channel_room_state_clone.borrow_mut(),
+                             &mut env_clone.last_sent_clone.borrow_mut());
+            });
+        }
+
+        {
+            let window_clone = self.window.clone();
+            let accounts_clone = self.accounts.clone();
+            self.window.borrow_mut().on_quit(move || {
+                save_session(&window_clone.borrow(), &accounts_clone.borrow());
             });
         }
 
         {
             let window_clone = self.window.clone();
-            let client_clone = self.client.clone();
+            let pending_logins_clone = self.pending_logins.clone();
+            let validator_clone = self.validator.clone();
+            self.window.borrow_mut().on_login(move |credentials, done| {
+                login(credentials,
+                      &mut window_clone.borrow_mut(),
+                      &mut pending_logins_clone.borrow_mut(),
+                      &validator_clone,
+                      done)
+            });
+        }
+
+        {
+            let window_clone = self.window.clone();
+            let accounts_clone = self.accounts.clone();
             let twitch_loader_clone = self.twitch_loader.clone();
-            let global_user_state_clone = self.global_user_state.clone();
-            let channel_user_state_clone = self.channel_user_state.clone();
+            let channel_room_state_clone = self.channel_room_state.clone();
+            let last_sent_clone = self.last_sent.clone();
+            let pending_logins_clone = self.pending_logins.clone();
+            let env_clone = env.clone();
 
             gtk::timeout_add(30, move ||
                 {
                     let mut window = window_clone.borrow_mut();
-                    let mut client = client_clone.borrow_mut();
+                    let mut accounts = accounts_clone.borrow_mut();
                     let mut twitch_loader = twitch_loader_clone.borrow_mut();
-                    let mut global_user_state = global_user_state_clone.borrow_mut();
-                    let mut channel_user_state = channel_user_state_clone.borrow_mut();
+                    let mut channel_room_state = channel_room_state_clone.borrow_mut();
+                    let mut last_sent = last_sent_clone.borrow_mut();
 
-                    if let Some(ref mut twitch_client) = *client
                     {
-                        while let Ok(msg) = twitch_client.receiver.try_recv()
+                        let mut pending_logins = pending_logins_clone.borrow_mut();
+                        let mut still_pending = Vec::new();
+                        for pending in pending_logins.drain(..)
                         {
-                            if let Err(e) = msg
+                            let received = pending.receiver.try_recv();
+                            match received
                             {
-                                match e
+                                Ok(result) => finish_login(pending, result, &mut window, &mut accounts),
+                                Err(TryRecvError::Empty) => still_pending.push(pending),
+                                Err(TryRecvError::Disconnected) =>
                                 {
-                                    ClientError::WebSocketError(_) =>
-                                    {
-                                        window.status_log_line("Disconnected from chat");
-                                        //return Continue(false);
-                                    },
-                                    _ =>
-                                    {
-                                        println!("Error: {}", e);
-                                    }
-                                }
+                                    let error = String::from("Login failed: the login thread stopped");
+                                    finish_login(pending, Err(error), &mut window, &mut accounts);
+                                },
                             }
-                            else if let Ok(message) = msg
+                        }
+                        *pending_logins = still_pending;
+                    }
+
+                    /* Every account is in every channel, so only one of them
+                     * shows what is said there.
+                     */
+                    let primary = accounts.iter().position(|account| account.client.is_some());
+                    let mut rejected = Vec::new();
+
+                    for (i, account) in accounts.iter_mut().enumerate()
+                    {
+                        let name = account.name();
+                        let displaying = primary == Some(i);
+                        let mut disconnected = false;
+                        if let Some(ref mut twitch_client) = account.client
+                        {
+                            while let Ok(msg) = twitch_client.receiver.try_recv()
                             {
-                                let command = message.command.clone();
-                                match command.as_str()
+                                if let Err(e) = msg
                                 {
-                                    "PRIVMSG" =>
+                                    match e
                                     {
-                                        let chan_opt = message.args.get(0)
-                                                                   .map(|s| s.clone());
-                                        if let Some(chan_name) = chan_opt
+                                        ClientError::WebSocketError(_) =>
+                                        {
+                                            window.status_log_line(&format!("Disconnected from chat ({})", name));
+                                            disconnected = true;
+                                            break;
+                                        },
+                                        _ =>
                                         {
-                                            window.channel_print_message(chan_name.clone(), message.into(), &mut twitch_loader);
+                                            println!("Error: {}", e);
                                         }
-                                    },
-                                    "NOTICE" =>
+                                    }
+                                }
+                                else if let Ok(message) = msg
+                                {
+                                    match TwitchMessage::from(message)
                                     {
-                                        let chan_opt = message.args.get(0)
-                                                                   .map(|s| s.clone());
-                                        if let Some(chan_name) = chan_opt
+                                        TwitchMessage::TwitchPrivmsg(_) |
+                                        TwitchMessage::TwitchUserNotice(_) |
+                                        TwitchMessage::TwitchRoomState(..) |
+                                        TwitchMessage::TwitchClearChat(_) |
+                                        TwitchMessage::TwitchClearMsg(_) |
+                                        TwitchMessage::TwitchHostTarget(_) if !displaying => {},
+                                        TwitchMessage::TwitchPrivmsg(privmsg) =>
+                                        {
+                                            window.channel_print_message(privmsg.to.clone(), TwitchMessage::TwitchPrivmsg(privmsg), &mut twitch_loader);
+                                        },
+                                        TwitchMessage::TwitchUserNotice(notice) =>
                                         {
-                                            if let Some(notice_message) = message.args.get(1)
+                                            window.channel_print_message(notice.channel.clone(), TwitchMessage::TwitchUserNotice(notice), &mut twitch_loader);
+                                        },
+                                        TwitchMessage::TwitchNotice(ref notice) if is_auth_failure(&notice.message) =>
+                                        {
+                                            window.status_log_line(&format!("Login failed for {}: {}", name, notice.message));
+                                            rejected.push(i);
+                                            disconnected = true;
+                                            break;
+                                        },
+                                        TwitchMessage::TwitchNotice(notice) =>
+                                        {
+                                            if window.has_channel(&notice.channel)
                                             {
-                                                window.channel_print_line(chan_name.clone(), &notice_message);
+                                                window.channel_print_line(notice.channel, &notice.message);
                                             }
-                                        }
-                                    },
-                                    "PING" =>
-                                    {
-                                        if let Some(value) = message.args.get(0)
+                                            else
+                                            {
+                                                window.status_log_line(&notice.message);
+                                            }
+                                        },
+                                        TwitchMessage::TwitchPing(value) =>
                                         {
-                                            twitch_client.sender.send_raw(&format!("PONG :{}", value)).unwrap();
-                                        }
-                                    },
-                                    "GLOBALUSERSTATE" =>
-                                    {
-                                        let msg: TwitchMessage = message.into();
-                                        /* Garenteed */
-                                        if let TwitchMessage::TwitchGlobalUserState(global_state) = msg
+                                            if let Err(e) = twitch_client.sender.send_raw(&format!("PONG :{}", value))
+                                            {
+                                                window.status_log_line(&format!("Could not answer PING: {}", ChatError::Send(e)));
+                                            }
+                                        },
+                                        TwitchMessage::TwitchReconnect =>
                                         {
-                                            *global_user_state = global_state;
-                                        }
-                                    },
-                                    "USERSTATE" =>
+                                            window.status_log_line(&format!("Server asked us to reconnect ({})", name));
+                                            disconnected = true;
+                                            break;
+                                        },
+                                        TwitchMessage::TwitchWhisper(whisper) =>
+                                        {
+                                            let user = whisper.user.clone();
+                                            if !window.has_channel(&user)
+                                            {
+                                                window.add_channel(user.clone(), on_text_callback, env_clone.clone());
+                                                /* Answer from the account that was whispered */
+                                                window.channel_set_identity(&user, &name);
+                                            }
+                                            window.channel_print_message(user.clone(), TwitchMessage::TwitchPrivmsg(whisper), &mut twitch_loader);
+                                            window.channel_mark_unread(user);
+                                        },
+                                        TwitchMessage::TwitchRoomState(chan, room_state) =>
+                                        {
+                                            let chan = normalize_channel(&chan);
+                                            let state = channel_room_state.entry(chan.clone())
+                                                                          .or_insert_with(TwitchRoomState::default);
+                                            state.update(&room_state);
+                                            window.channel_set_room_state(chan, state);
+                                        },
+                                        TwitchMessage::TwitchClearChat(clear) =>
+                                        {
+                                            window.channel_clear_chat(clear);
+                                        },
+                                        TwitchMessage::TwitchClearMsg(clear) =>
+                                        {
+                                            window.channel_delete_message(clear);
+                                        },
+                                        TwitchMessage::TwitchHostTarget(host) =>
+                                        {
+                                            let line = match (host.target, host.viewers)
+                                            {
+                                                (Some(target), Some(viewers)) => format!("Now hosting {} for {} viewers", target, viewers),
+                                                (Some(target), None) => format!("Now hosting {}", target),
+                                                (None, _) => String::from("Exited host mode"),
+                                            };
+                                            window.channel_print_line(host.channel, &line);
+                                        },
+                                        TwitchMessage::TwitchGlobalUserState(global_state) =>
+                                        {
+                                            account.global_user_state = global_state;
+                                        },
+                                        TwitchMessage::TwitchUserState(chan, user_state) =>
+                                        {
+                                            account.channel_user_state.insert(normalize_channel(&chan), user_state);
+                                        },
+                                        TwitchMessage::TwitchJoin(chan, user) =>
+                                        {
+                                            window.status_log_line(&format!("{} joined {}", user, chan));
+                                        },
+                                        TwitchMessage::TwitchPart(chan, user) =>
+                                        {
+                                            window.status_log_line(&format!("{} left {}", user, chan));
+                                        },
+                                        /* Only our own name without the membership capability */
+                                        TwitchMessage::TwitchNames(..) |
+                                        TwitchMessage::TwitchEndOfNames(_) => {},
+                                        TwitchMessage::TwitchNumeric(_, text) =>
+                                        {
+                                            if displaying
+                                            {
+                                                window.status_log_line(&text);
+                                            }
+                                        },
+                                        /* Never sent by the server */
+                                        TwitchMessage::TwitchEcho(..) => {},
+                                        TwitchMessage::Unknown(raw) =>
+                                        {
+                                            if displaying
+                                            {
+                                                window.status_log_line(&raw);
+                                            }
+                                        },
+                                    }
+                                }
+                            }
+
+                            if !disconnected
+                            {
+                                if let Err((outgoing, e)) = twitch_client.flush(&account.channel_user_state, &mut last_sent)
+                                {
+                                    report_unsent(&mut window, outgoing, &e);
+                                }
+                            }
+                        }
+
+                        if disconnected
+                        {
+                            if let Some(mut old_client) = account.client.take()
+                            {
+                                for outgoing in old_client.queue.drain_messages()
+                                {
+                                    report_unsent(&mut window, outgoing, &ChatError::NotConnected);
+                                }
+                            }
+                            /* Connections that drop right away keep backing off */
+                            let stable = account.connected_at
+                                                .take()
+                                                .map(|at| at.elapsed() >= Duration::from_secs(STABLE_CONNECTION_SECS))
+                                                .unwrap_or(false);
+                            if stable
+                            {
+                                account.reconnect_attempt = 0;
+                            }
+                            let delay = reconnect_delay(account.reconnect_attempt);
+                            window.status_log_line(&format!("Reconnecting {} in {} s", name, delay.as_secs()));
+                            account.retry_at = Some(Instant::now() + delay);
+                        }
+
+                        if account.client.is_none() && !rejected.contains(&i)
+                        {
+                            let connected = match account.connecting
+                            {
+                                Some(ref connecting) => match connecting.try_recv()
+                                {
+                                    Ok(result) => Some(result),
+                                    Err(TryRecvError::Empty) => None,
+                                    Err(TryRecvError::Disconnected) => Some(Err(ChatError::NotConnected)),
+                                },
+                                None => None,
+                            };
+                            let due = account.connecting.is_none() &&
+                                      account.retry_at
+                                             .map(|at| Instant::now() >= at)
+                                             .unwrap_or(false);
+                            if let Some(result) = connected
+                            {
+                                account.connecting = None;
+                                match result
+                                {
+                                    Ok(mut new_client) =>
                                     {
-                                        let msg: TwitchMessage = message.into();
-                                        /* Garenteed */
-                                        if let TwitchMessage::TwitchUserState(chan, user_state) = msg
+                                        for channel in window.channel_names()
                                         {
-                                            channel_user_state.insert(chan, user_state);
+                                            new_client.queue_join(&channel);
                                         }
+                                        window.status_log_line(&format!("Reconnected to chat ({})", name));
+                                        account.connected_at = Some(Instant::now());
+                                        account.client = Some(new_client);
                                     },
-                                    _ =>
+                                    Err(e) =>
                                     {
-                                        window.status_log_line(&message.raw);
-                                    }
+                                        let delay = reconnect_delay(account.reconnect_attempt);
+                                        window.status_log_line(&format!("Reconnect failed: {}, retrying in {} s", e, delay.as_secs()));
+                                        account.retry_at = Some(Instant::now() + delay);
+                                    },
                                 }
                             }
+                            else if due
+                            {
+                                account.reconnect_attempt += 1;
+                                window.status_log_line(&format!("Reconnecting {} (attempt {})", name, account.reconnect_attempt));
+                                account.retry_at = None;
+                                account.connecting = Some(connect_in_background(&account.credentials));
+                            }
                         }
                     }
+
+                    /* A refused token would fail again on every reconnect */
+                    if !rejected.is_empty()
+                    {
+                        let mut i = 0;
+                        accounts.retain(|_| {
+                            i += 1;
+                            !rejected.contains(&(i - 1))
+                        });
+                        show_accounts(&mut window, &accounts);
+                    }
+
+                    for channel in window.channel_names()
+                    {
+                        let queued = accounts.iter()
+                                             .filter_map(|account| account.client.as_ref())
+                                             .map(|twitch_client| twitch_client.queue.queued_for(&channel))
+                                             .sum();
+                        window.channel_set_queued(channel, queued);
+                    }
+
                     Continue(true)
                 }
             );
@@ -1,15 +1,30 @@
-use twitch_message::{TwitchMessage, TwitchEmoteRange};
-use twitch_image_loader::TwitchImageLoader;
+use twitch_message::{channel_login, cheer_color, TwitchMessage, TwitchPrivmsg, TwitchReply, TwitchRoomState, TwitchClearChat, TwitchClearMsg};
+use twitch_image_loader::{ImageKey, ImageResult, TwitchImageLoader};
+use ui::pixbuf_cache::PixbufCache;
+use emote_match::{find_cheers, EmoteMatcher};
 
+use gdk;
 use gdk_pixbuf;
 use gtk;
 use gtk::prelude::*;
 use gdk::enums::key;
+use glib::ToValue;
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 
 use std::rc::Rc;
 use std::cell::{Cell, RefCell};
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+/* How much of the parent message a reply header shows */
+const REPLY_BODY_CHARS: usize = 60;
+/* Lines kept in a tab. Older ones are dropped TRIM_LINES at a time, so
+ * the marks pointing into them are not searched on every message.
+ */
+const BACKLOG_LINES: i32 = 5000;
+const TRIM_LINES: i32 = 500;
+/* Shown where a badge could not be loaded */
+const MISSING_BADGE: &'static str = "<span foreground=\"#808080\">\u{25A1}</span>";
 
 struct ChannelPanelRefCell
 {
@@ -25,24 +40,64 @@ pub struct ChannelWidget
 {
     name: String,
     pane: gtk::Box,
+    tab_label: gtk::Label,
     backlog: gtk::TextView,
+    room_state_label: gtk::Label,
+    /* Which logged in account this tab sends as */
+    identity_box: gtk::ComboBoxText,
     empty: bool,
+    /* Messages held back by the rate limiter */
+    queued: usize,
+    /* Cleared on drop so pending image callbacks stop */
+    alive: Rc<Cell<bool>>,
     refcell_data: Rc<RefCell<ChannelPanelRefCell>>,
+    /* Start and end of every line printed for a user, by login */
+    user_lines: HashMap<String, Vec<(gtk::TextMark, gtk::TextMark)>>,
+    /* Start and end of the text of every message, by message id */
+    message_ranges: HashMap<String, (gtk::TextMark, gtk::TextMark)>,
+    /* The latest message of every user, by login, as a reply would refer to it */
+    reply_targets: HashMap<String, TwitchReply>,
+    /* Tags made for a single line, with where they end. Taken out of the tag
+     * table once the line is trimmed.
+     */
+    line_tags: Vec<(gtk::TextMark, gtk::TextTag)>,
+    /* Shared with the other tabs */
+    pixbufs: PixbufCache,
+    cleared_tag: gtk::TextTag,
+    notice_tag: gtk::TextTag,
+    cheer_tag: gtk::TextTag,
+    reply_tag: gtk::TextTag,
 }
 
 impl ChannelWidget
 {
-    pub fn new(name: String) -> ChannelWidget
+    pub fn new(name: String, pixbufs: PixbufCache) -> ChannelWidget
     {
         let pane = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let tab_label = gtk::Label::new(Some(&name));
         let backlog_scroll = gtk::ScrolledWindow::new(None, None);
         let backlog = gtk::TextView::new();
+        let room_state_label = gtk::Label::new(None);
+        let identity_box = gtk::ComboBoxText::new();
         let entry = gtk::Entry::new();
+        let input_row = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         let mut entry_backlog = VecDeque::new();
 
+        input_row.pack_start(&identity_box, false, false, 0);
+        input_row.pack_start(&entry, true, true, 0);
+
         backlog_scroll.add(&backlog);
         pane.pack_start(&backlog_scroll, true, true, 0);
-        pane.pack_start(&entry, false, false, 0);
+        pane.pack_start(&room_state_label, false, false, 2);
+        pane.pack_start(&input_row, false, false, 0);
+
+        /* Only shown when there is more than one account to pick from */
+        identity_box.set_no_show_all(true);
+
+        /* Only shown once the channel has a mode set */
+        room_state_label.set_no_show_all(true);
+        room_state_label.set_xalign(0.0);
+        room_state_label.set_margin_start(10);
 
         backlog.set_wrap_mode(gtk::WrapMode::WordChar);
         backlog.set_pixels_below_lines(5);
@@ -51,6 +106,29 @@ impl ChannelWidget
 
 //        backlog_scroll.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Always);
 
+        /* Applied to lines removed by a ban, timeout or chat clear */
+        let cleared_tag = gtk::TextTag::new(Some("cleared"));
+        cleared_tag.set_property("strikethrough", &true.to_value()).unwrap();
+        cleared_tag.set_property("foreground", &"#808080".to_value()).unwrap();
+        backlog.get_buffer().unwrap().get_tag_table().unwrap().add(&cleared_tag);
+
+        /* Subs, raids and other USERNOTICE events */
+        let notice_tag = gtk::TextTag::new(Some("notice"));
+        notice_tag.set_property("paragraph-background", &"#32283f".to_value()).unwrap();
+        notice_tag.set_property("left-margin", &20.to_value()).unwrap();
+        backlog.get_buffer().unwrap().get_tag_table().unwrap().add(&notice_tag);
+
+        /* Messages that came with bits */
+        let cheer_tag = gtk::TextTag::new(Some("cheer"));
+        cheer_tag.set_property("paragraph-background", &"#2b2b3d".to_value()).unwrap();
+        backlog.get_buffer().unwrap().get_tag_table().unwrap().add(&cheer_tag);
+
+        /* The "replying to" line above a reply */
+        let reply_tag = gtk::TextTag::new(Some("reply"));
+        reply_tag.set_property("foreground", &"#a0a0a0".to_value()).unwrap();
+        reply_tag.set_property("scale", &0.85.to_value()).unwrap();
+        backlog.get_buffer().unwrap().get_tag_table().unwrap().add(&reply_tag);
+
         entry_backlog.push_front("".to_string());
 
         let refcell_data = Rc::new(RefCell::new(ChannelPanelRefCell
@@ -150,21 +228,108 @@ impl ChannelWidget
             });
         }
 
+        {
+            /* Clear the unread marker once the tab is shown */
+            let tab_label_clone = tab_label.clone();
+            let name_clone = name.clone();
+            pane.connect_map(move |_| {
+                tab_label_clone.set_text(&name_clone);
+            });
+        }
+
         ChannelWidget
         {
             name: name,
             pane: pane,
+            tab_label: tab_label,
             backlog: backlog,
+            room_state_label: room_state_label,
+            identity_box: identity_box,
             empty: true,
+            queued: 0,
+            alive: Rc::new(Cell::new(true)),
             refcell_data: refcell_data,
+            user_lines: HashMap::new(),
+            message_ranges: HashMap::new(),
+            reply_targets: HashMap::new(),
+            line_tags: Vec::new(),
+            pixbufs: pixbufs,
+            cleared_tag: cleared_tag,
+            notice_tag: notice_tag,
+            cheer_tag: cheer_tag,
+            reply_tag: reply_tag,
         }
     }
 
+    /* Keeps the current choice if that account is still logged in */
+    pub fn set_identities(&mut self, identities: &[String])
+    {
+        let current = self.identity_box.get_active_id();
+        self.identity_box.remove_all();
+        for identity in identities
+        {
+            self.identity_box.append(Some(identity.as_str()), identity);
+        }
+        let keep = current.map(|id| self.identity_box.set_active_id(Some(id.as_str())))
+                          .unwrap_or(false);
+        if !keep
+        {
+            if let Some(first) = identities.first()
+            {
+                self.identity_box.set_active_id(Some(first.as_str()));
+            }
+        }
+        self.identity_box.set_visible(identities.len() > 1);
+    }
+
+    pub fn get_identity(&self) -> Option<String>
+    {
+        self.identity_box.get_active_id()
+    }
+
+    pub fn set_identity(&mut self, identity: &str)
+    {
+        self.identity_box.set_active_id(Some(identity));
+    }
+
     pub fn get_pane(&self) -> &gtk::Box
     {
         &self.pane
     }
 
+    pub fn get_tab_label(&self) -> &gtk::Label
+    {
+        &self.tab_label
+    }
+
+    pub fn set_queued(&mut self, queued: usize)
+    {
+        if queued == self.queued
+        {
+            return;
+        }
+        self.queued = queued;
+
+        let internal = self.refcell_data.borrow();
+        if queued == 0
+        {
+            internal.entry.set_placeholder_text(None);
+        }
+        else
+        {
+            let text = format!("{} message(s) delayed by the rate limit", queued);
+            internal.entry.set_placeholder_text(Some(&text));
+        }
+    }
+
+    pub fn mark_unread(&mut self)
+    {
+        if !self.pane.get_mapped()
+        {
+            self.tab_label.set_markup(&format!("<b>{}</b>", escape_markup(&self.name)));
+        }
+    }
+
     pub fn println(&mut self, line: &str)
     {
         let buf = self.backlog.get_buffer().unwrap();
@@ -178,6 +343,43 @@ impl ChannelWidget
             self.empty = false;
         }
         buf.insert(&mut end, line);
+        self.trim_backlog();
+    }
+
+    /* Clicking the line puts the text back in the entry and sends it again */
+    pub fn print_unsent(&mut self, text: &str, reason: &str)
+    {
+        self.println(&format!("Message not sent ({}), click to retry: {}", reason, text));
+
+        let buf = self.backlog.get_buffer().unwrap();
+        let retry_tag = gtk::TextTag::new(None);
+        retry_tag.set_property("foreground", &"#e05050".to_value()).unwrap();
+        self.add_line_tag(&buf, &retry_tag, &buf.get_end_iter());
+
+        let entry = self.refcell_data.borrow().entry.clone();
+        let text = String::from(text);
+        retry_tag.connect_event(move |_, _, event, _| {
+            if event.get_event_type() == gdk::EventType::ButtonPress
+            {
+                entry.set_text(&text);
+                entry.activate();
+            }
+            Inhibit(false)
+        });
+
+        let end = buf.get_end_iter();
+        let mut start = buf.get_end_iter();
+        start.set_line_offset(0);
+        buf.apply_tag(&retry_tag, &start, &end);
+    }
+
+    /* Adds a tag that is only applied before end to the tag table */
+    fn add_line_tag(&mut self, buf: &gtk::TextBuffer, tag: &gtk::TextTag, end: &gtk::TextIter)
+    {
+        buf.get_tag_table().unwrap().add(tag);
+        let end_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&end_mark, end);
+        self.line_tags.push((end_mark, tag.clone()));
     }
 
     pub fn print_message(&mut self, message: TwitchMessage, til: &mut TwitchImageLoader)
@@ -198,17 +400,57 @@ impl ChannelWidget
         {
             TwitchMessage::TwitchPrivmsg(privmsg) =>
             {
-                for badge in privmsg.badges
+                self.print_privmsg(&buf, &mut end, privmsg, til);
+            },
+            TwitchMessage::TwitchUserNotice(notice) =>
+            {
+                let notice_start_mark = gtk::TextMark::new(None, true);
+                buf.add_mark(&notice_start_mark, &end);
+
+                let system_msg = if notice.system_msg.is_empty()
+                {
+                    notice.msg_id
+                }
+                else
+                {
+                    notice.system_msg
+                };
+                buf.insert_markup(&mut end, &format!("<b>{}</b>", escape_markup(&system_msg)));
+
+                if let Some(privmsg) = notice.privmsg
+                {
+                    buf.insert(&mut end, "\n");
+                    self.print_privmsg(&buf, &mut end, privmsg, til);
+                }
+
+                let notice_start = buf.get_iter_at_mark(&notice_start_mark);
+                buf.apply_tag(&self.notice_tag, &notice_start, &buf.get_end_iter());
+                buf.delete_mark(&notice_start_mark);
+            },
+            TwitchMessage::TwitchEcho(mut privmsg, emote_sets) =>
+            {
+                let line_start_mark = gtk::TextMark::new(None, true);
+                buf.add_mark(&line_start_mark, &end);
+
+                if let Some(ref reply) = privmsg.reply
+                {
+                    self.print_reply_header(&buf, &mut end, reply);
+                }
+
+                for badge in &privmsg.badges
                 {
                     let receiver;
+                    let image_key;
 
-                    if badge.set == "subscriber"
+                    if badge.set == "subscriber" && self.name.starts_with("#")
                     {
-                        receiver = til.get_subscriber_badge(badge.clone(), self.name[1..].into());
+                        receiver = til.get_subscriber_badge(badge.clone(), channel_login(&self.name).into());
+                        image_key = ImageKey::SubBadge(channel_login(&self.name).into(), badge.clone());
                     }
                     else
                     {
                         receiver = til.get_badge(badge.clone());
+                        image_key = ImageKey::Badge(badge.clone());
                     }
 
                     let badge_mark = gtk::TextMark::new(None, true);
@@ -217,24 +459,26 @@ impl ChannelWidget
 
                     let buf_clone = buf.clone();
                     let mark_clone = badge_mark.clone();
+                    let pixbufs_clone = self.pixbufs.clone();
+                    let alive_clone = self.alive.clone();
                     let async = move || {
-                        if let Ok(bin) = receiver.try_recv()
+                        if !alive_clone.get()
                         {
-                            println!("=====================LOADED BIN FOR THING");
-                            let pbl = gdk_pixbuf::PixbufLoader::new();
-                            pbl.loader_write(&bin).unwrap();
-                            pbl.close().unwrap();
-                            if let Some(pixbuf) = pbl.get_pixbuf()
-                            {
-                                let mut iter = buf_clone.get_iter_at_mark(&mark_clone);
-                                buf_clone.insert_pixbuf(&mut iter, &pixbuf);
-                            }
-                            Continue(false)
+                            return Continue(false);
                         }
-                        else
+
+                        let bin = match poll_image(&receiver)
                         {
-                            Continue(true)
+                            Some(bin) => bin,
+                            None => return Continue(true),
+                        };
+                        let mut iter = buf_clone.get_iter_at_mark(&mark_clone);
+                        match bin.and_then(|bin| pixbufs_clone.decode(image_key.clone(), &bin))
+                        {
+                            Some(pixbuf) => buf_clone.insert_pixbuf(&mut iter, &pixbuf),
+                            None => buf_clone.insert_markup(&mut iter, MISSING_BADGE),
                         }
+                        Continue(false)
                     };
 
                     if let Continue(true) = async()
@@ -259,256 +503,610 @@ impl ChannelWidget
 
                 buf.insert(&mut end, &privmsg.message);
 
-                /* Generate marks first before deleting/inserting */
-                let mut emote_vec = Vec::new();
-                for emote in privmsg.emotes
-                {
-                    let receiver = til.get_emote(emote.id);
+                let line_end_mark = gtk::TextMark::new(None, true);
+                buf.add_mark(&line_end_mark, &end);
+                self.user_lines.entry(privmsg.user.clone())
+                               .or_insert_with(Vec::new)
+                               .push((line_start_mark, line_end_mark));
+
+                /* Every set has to be in before any emote is marked, since
+                 * replacing one moves the text after it.
+                 */
+                let mut set_receivers: Vec<_> = emote_sets.into_iter()
+                                                          .map(|emote_set| til.get_emote_set(emote_set))
+                                                          .collect();
+                let mut emotes = Vec::new();
 
-                    let mut range_marks = Vec::new();
-                    for range in emote.ranges
+                let buf_clone = buf.clone();
+                let privmsg_clone = privmsg.clone();
+                let mut til_clone = til.clone();
+                let start_of_message_mark_clone = start_of_message_mark.clone();
+                let alive_clone = self.alive.clone();
+                let pixbufs_clone = self.pixbufs.clone();
+                let mut async = move || {
+                    if !alive_clone.get()
                     {
-                        let mut start = buf.get_iter_at_mark(&start_of_message_mark);
-                        let mut end = buf.get_iter_at_mark(&start_of_message_mark);
-                        start.forward_chars(range.0 as i32);
-                        end.forward_chars(range.1 as i32 + 1);
-
-                        let start_mark = gtk::TextMark::new(None, true);
-                        buf.add_mark(&start_mark, &start);
-                        let end_mark = gtk::TextMark::new(None, true);
-                        buf.add_mark(&end_mark, &end);
-                        range_marks.push((start_mark, end_mark));
+                        return Continue(false);
                     }
-                    emote_vec.push((receiver, range_marks));
-                }
 
-                for emote in emote_vec
-                {
-                    let buf_clone = buf.clone();
-                    let range_marks_clone = emote.1;
-                    let receiver = emote.0;
-                    let async = move || {
-                        if let Ok(bin) = receiver.try_recv()
+                    let mut waiting = Vec::new();
+                    for receiver in set_receivers.drain(..)
+                    {
+                        match poll_image(&receiver)
+                        {
+                            Some(Some(set_emotes)) => emotes.extend(set_emotes),
+                            /* Those emotes stay as plain text */
+                            Some(None) => {},
+                            None => waiting.push(receiver),
+                        }
+                    }
+                    set_receivers = waiting;
+                    if !set_receivers.is_empty()
+                    {
+                        return Continue(true);
+                    }
+
+                    /* Same form as the emotes tag, so it is placed the same way.
+                     * Generate marks first before deleting/inserting.
+                     */
+                    let mut emote_vec = Vec::new();
+                    for emote_range in EmoteMatcher::new(&emotes).find(&privmsg_clone.message)
+                    {
+                        let emote_ranges = emote_range.char_ranges(&privmsg_clone.message);
+                        if emote_ranges.is_empty()
+                        {
+                            continue;
+                        }
+
+                        let image_receiver = til_clone.get_emote(emote_range.id);
+
+                        let mut range_marks = Vec::new();
+                        for range in emote_ranges
                         {
-                            println!("=====================LOADED BIN FOR EMOTE");
-                            let pbl = gdk_pixbuf::PixbufLoader::new();
-                            pbl.loader_write(&bin).unwrap();
-                            pbl.close().unwrap();
+                            let mut start = buf_clone.get_iter_at_mark(&start_of_message_mark_clone);
+                            let mut end = buf_clone.get_iter_at_mark(&start_of_message_mark_clone);
+                            start.forward_chars(range.0 as i32);
+                            end.forward_chars(range.1 as i32);
+
+                            let start_mark = gtk::TextMark::new(None, true);
+                            buf_clone.add_mark(&start_mark, &start);
+                            let end_mark = gtk::TextMark::new(None, true);
+                            buf_clone.add_mark(&end_mark, &end);
+                            range_marks.push((start_mark, end_mark));
+                        }
+                        emote_vec.push((image_receiver, range_marks, ImageKey::Emote(emote_range.id)));
+                    }
+                    buf_clone.delete_mark(&start_of_message_mark_clone);
+
+                    for (image_receiver, range_marks, image_key) in emote_vec
+                    {
+                        let buf_clone_clone = buf_clone.clone();
+                        let alive_clone_clone = alive_clone.clone();
+                        let pixbufs_clone = pixbufs_clone.clone();
+                        let image_async = move || {
+                            if !alive_clone_clone.get()
+                            {
+                                return Continue(false);
+                            }
 
-                            if let Some(pixbuf) = pbl.get_pixbuf()
+                            let bin = match poll_image(&image_receiver)
                             {
-                                for range in &range_marks_clone
+                                Some(bin) => bin,
+                                None => return Continue(true),
+                            };
+                            /* A missing emote keeps its code as text */
+                            if let Some(pixbuf) = bin.and_then(|bin| pixbufs_clone.decode(image_key.clone(), &bin))
+                            {
+                                for range in &range_marks
                                 {
-                                    let mut start = buf_clone.get_iter_at_mark(&range.0);
-                                    let mut end = buf_clone.get_iter_at_mark(&range.1);
-                                    buf_clone.delete(&mut start, &mut end);
-                                    buf_clone.insert_pixbuf(&mut start, &pixbuf);
+                                    replace_with_pixbuf(&buf_clone_clone, &range.0, &range.1, &pixbuf);
                                 }
                             }
+                            for range in &range_marks
+                            {
+                                buf_clone_clone.delete_mark(&range.0);
+                                buf_clone_clone.delete_mark(&range.1);
+                            }
                             Continue(false)
-                        }
-                        else
+                        };
+
+                        if let Continue(true) = image_async()
                         {
-                            Continue(true)
+                            gtk::timeout_add(30, image_async);
                         }
-                    };
-
-                    if let Continue(true) = async()
-                    {
-                        gtk::timeout_add(30, async);
                     }
-                    /* Needed if we use end after this */
-//                    else
-//                    {
-//                        end = buf.get_end_iter();
-//                    }
-                }
 
-                buf.delete_mark(&start_of_message_mark);
+                    Continue(false)
+                };
+
+                if let Continue(true) = async()
+                {
+                    gtk::timeout_add(30, async);
+                }
             },
-            TwitchMessage::TwitchEcho(mut privmsg, emote_sets) =>
+            TwitchMessage::Unknown(raw) =>
             {
-                for badge in &privmsg.badges
+                buf.insert(&mut end, &raw);
+            },
+            _ =>
+            {
+                unreachable!();
+            },
+        }
+
+        self.trim_backlog();
+    }
+
+    /* Drops the oldest lines once there are too many, along with the marks
+     * kept for them.
+     */
+    fn trim_backlog(&mut self)
+    {
+        let buf = self.backlog.get_buffer().unwrap();
+        let lines = buf.get_line_count();
+        if lines <= BACKLOG_LINES + TRIM_LINES
+        {
+            return;
+        }
+
+        let cut_offset = buf.get_iter_at_line(lines - BACKLOG_LINES).get_offset();
+
+        for user_lines in self.user_lines.values_mut()
+        {
+            user_lines.retain(|&(ref start_mark, ref end_mark)| !forget_if_before(&buf, start_mark, end_mark, cut_offset));
+        }
+        self.user_lines.retain(|_, user_lines| !user_lines.is_empty());
+        self.message_ranges.retain(|_, &mut (ref start_mark, ref end_mark)| !forget_if_before(&buf, start_mark, end_mark, cut_offset));
+        let message_ranges = &self.message_ranges;
+        self.reply_targets.retain(|_, target| message_ranges.contains_key(&target.parent_id));
+        let tag_table = buf.get_tag_table().unwrap();
+        self.line_tags.retain(|&(ref end_mark, ref tag)| {
+            if buf.get_iter_at_mark(end_mark).get_offset() >= cut_offset
+            {
+                return true;
+            }
+            tag_table.remove(tag);
+            buf.delete_mark(end_mark);
+            false
+        });
+
+        /* Taken after the tags are gone, removing them changes the buffer */
+        let mut start = buf.get_start_iter();
+        let mut cut = buf.get_iter_at_offset(cut_offset);
+        buf.delete(&mut start, &mut cut);
+    }
+
+    fn print_privmsg(&mut self, buf: &gtk::TextBuffer, end: &mut gtk::TextIter, privmsg: TwitchPrivmsg, til: &mut TwitchImageLoader)
+    {
+        let line_start_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&line_start_mark, end);
+
+        if let Some(ref reply) = privmsg.reply
+        {
+            self.print_reply_header(buf, end, reply);
+        }
+
+        for badge in privmsg.badges
+        {
+            let receiver;
+            let image_key;
+
+            if badge.set == "subscriber" && self.name.starts_with("#")
+            {
+                receiver = til.get_subscriber_badge(badge.clone(), channel_login(&self.name).into());
+                image_key = ImageKey::SubBadge(channel_login(&self.name).into(), badge.clone());
+            }
+            else
+            {
+                receiver = til.get_badge(badge.clone());
+                image_key = ImageKey::Badge(badge.clone());
+            }
+
+            let badge_mark = gtk::TextMark::new(None, true);
+            buf.add_mark(&badge_mark, end);
+            buf.insert(end, " ");
+
+            let buf_clone = buf.clone();
+            let mark_clone = badge_mark.clone();
+            let pixbufs_clone = self.pixbufs.clone();
+            let alive_clone = self.alive.clone();
+            let async = move || {
+                if !alive_clone.get()
                 {
-                    let receiver;
+                    return Continue(false);
+                }
 
-                    if badge.set == "subscriber"
-                    {
-                        receiver = til.get_subscriber_badge(badge.clone(), self.name[1..].into());
-                    }
-                    else
-                    {
-                        receiver = til.get_badge(badge.clone());
-                    }
+                let bin = match poll_image(&receiver)
+                {
+                    Some(bin) => bin,
+                    None => return Continue(true),
+                };
+                let mut iter = buf_clone.get_iter_at_mark(&mark_clone);
+                match bin.and_then(|bin| pixbufs_clone.decode(image_key.clone(), &bin))
+                {
+                    Some(pixbuf) => buf_clone.insert_pixbuf(&mut iter, &pixbuf),
+                    None => buf_clone.insert_markup(&mut iter, MISSING_BADGE),
+                }
+                Continue(false)
+            };
 
-                    let badge_mark = gtk::TextMark::new(None, true);
-                    buf.add_mark(&badge_mark, &end);
-                    buf.insert(&mut end, " ");
+            if let Continue(true) = async()
+            {
+                gtk::timeout_add(30, async);
+            }
+            else
+            {
+                *end = buf.get_end_iter();
+            }
+        }
 
-                    let buf_clone = buf.clone();
-                    let mark_clone = badge_mark.clone();
-                    let async = move || {
-                        if let Ok(bin) = receiver.try_recv()
-                        {
-                            println!("=====================LOADED BIN FOR THING");
-                            let pbl = gdk_pixbuf::PixbufLoader::new();
-                            pbl.loader_write(&bin).unwrap();
-                            pbl.close().unwrap();
-                            if let Some(pixbuf) = pbl.get_pixbuf()
-                            {
-                                let mut iter = buf_clone.get_iter_at_mark(&mark_clone);
-                                buf_clone.insert_pixbuf(&mut iter, &pixbuf);
-                            }
-                            Continue(false)
-                        }
-                        else
-                        {
-                            Continue(true)
-                        }
-                    };
+        let msg = format!("<span foreground=\"#{:02x}{:02x}{:02x}\" font=\"bold\">{}</span>: ",
+                          privmsg.color.0,
+                          privmsg.color.1,
+                          privmsg.color.2,
+                          privmsg.name);
+        buf.insert_markup(end, &msg);
 
-                    if let Continue(true) = async()
-                    {
-                        gtk::timeout_add(30, async);
-                    }
-                    else
+        let start_of_message_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&start_of_message_mark, end);
+
+        buf.insert(end, &privmsg.message);
+
+        let end_of_message_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&end_of_message_mark, end);
+
+        /* Generate marks first before deleting/inserting */
+        let mut cheer_vec = Vec::new();
+        if privmsg.bits > 0
+        {
+            let total = cheer_color(privmsg.bits);
+            buf.insert_markup(end, &format!("  <span foreground=\"#{:02x}{:02x}{:02x}\" font=\"bold\">{} bits</span>",
+                                            total.0,
+                                            total.1,
+                                            total.2,
+                                            privmsg.bits));
+            cheer_vec = self.mark_cheermotes(buf, &start_of_message_mark, &privmsg.message, til);
+        }
+
+        let mut emote_vec = Vec::new();
+        for emote in privmsg.emotes
+        {
+            let receiver = til.get_emote(emote.id);
+
+            let mut range_marks = Vec::new();
+            for range in emote.char_ranges(&privmsg.message)
+            {
+                let mut start = buf.get_iter_at_mark(&start_of_message_mark);
+                let mut end = buf.get_iter_at_mark(&start_of_message_mark);
+                start.forward_chars(range.0 as i32);
+                end.forward_chars(range.1 as i32);
+
+                let start_mark = gtk::TextMark::new(None, true);
+                buf.add_mark(&start_mark, &start);
+                let end_mark = gtk::TextMark::new(None, true);
+                buf.add_mark(&end_mark, &end);
+                range_marks.push((start_mark, end_mark));
+            }
+            emote_vec.push((receiver, range_marks, ImageKey::Emote(emote.id)));
+        }
+
+        self.print_cheermotes(buf, cheer_vec);
+
+        for emote in emote_vec
+        {
+            let buf_clone = buf.clone();
+            let range_marks_clone = emote.1;
+            let receiver = emote.0;
+            let image_key = emote.2;
+            let alive_clone = self.alive.clone();
+            let pixbufs_clone = self.pixbufs.clone();
+            let async = move || {
+                if !alive_clone.get()
+                {
+                    return Continue(false);
+                }
+
+                let bin = match poll_image(&receiver)
+                {
+                    Some(bin) => bin,
+                    None => return Continue(true),
+                };
+                /* A missing emote keeps its code as text */
+                if let Some(pixbuf) = bin.and_then(|bin| pixbufs_clone.decode(image_key.clone(), &bin))
+                {
+                    for range in &range_marks_clone
                     {
-                        end = buf.get_end_iter();
+                        replace_with_pixbuf(&buf_clone, &range.0, &range.1, &pixbuf);
                     }
                 }
+                Continue(false)
+            };
 
-                let msg = format!("<span foreground=\"#{:02x}{:02x}{:02x}\" font=\"bold\">{}</span>: ",
-                                  privmsg.color.0,
-                                  privmsg.color.1,
-                                  privmsg.color.2,
-                                  privmsg.name);
-                buf.insert_markup(&mut end, &msg);
+            if let Continue(true) = async()
+            {
+                gtk::timeout_add(30, async);
+            }
+            /* Needed if we use end after this */
+//            else
+//            {
+//                *end = buf.get_end_iter();
+//            }
+        }
 
-                let start_of_message_mark = gtk::TextMark::new(None, true);
-                buf.add_mark(&start_of_message_mark, &end);
+        if privmsg.id.is_empty()
+        {
+            buf.delete_mark(&start_of_message_mark);
+            buf.delete_mark(&end_of_message_mark);
+        }
+        else
+        {
+            self.reply_targets.insert(privmsg.user.clone(), TwitchReply {
+                parent_id: privmsg.id.clone(),
+                parent_user: privmsg.user.clone(),
+                parent_name: privmsg.name.clone(),
+                parent_body: privmsg.message.clone(),
+            });
 
-                buf.insert(&mut end, &privmsg.message);
+            self.message_ranges.insert(privmsg.id, (start_of_message_mark, end_of_message_mark));
+        }
+
+        if privmsg.bits > 0
+        {
+            let line_start = buf.get_iter_at_mark(&line_start_mark);
+            buf.apply_tag(&self.cheer_tag, &line_start, &buf.get_end_iter());
+        }
+
+        let line_end_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&line_end_mark, &buf.get_end_iter());
+        self.user_lines.entry(privmsg.user)
+                       .or_insert_with(Vec::new)
+                       .push((line_start_mark, line_end_mark));
+    }
+
+    /* A dimmed "↳ replying to @user: …" line. Clicking it scrolls to the
+     * parent message if that is still in the backlog.
+     */
+    fn print_reply_header(&self, buf: &gtk::TextBuffer, end: &mut gtk::TextIter, reply: &TwitchReply)
+    {
+        let header_mark = gtk::TextMark::new(None, true);
+        buf.add_mark(&header_mark, end);
+        buf.insert(end, &format!("\u{21b3} replying to @{}: {}\n",
+                                 reply.parent_name,
+                                 ellipsize(&reply.parent_body, REPLY_BODY_CHARS)));
+        let header_start = buf.get_iter_at_mark(&header_mark);
+        buf.apply_tag(&self.reply_tag, &header_start, end);
+
+        if let Some(&(ref parent_mark, _)) = self.message_ranges.get(&reply.parent_id)
+        {
+            let jump_tag = gtk::TextTag::new(None);
+            buf.get_tag_table().unwrap().add(&jump_tag);
 
-                for emote_set in emote_sets
+            let backlog_clone = self.backlog.clone();
+            let parent_mark_clone = parent_mark.clone();
+            jump_tag.connect_event(move |_, _, event, _| {
+                /* The mark goes away if the parent is deleted or chat is cleared */
+                if event.get_event_type() == gdk::EventType::ButtonPress && !parent_mark_clone.get_deleted()
                 {
-                    let receiver = til.get_emote_set(emote_set);
+                    backlog_clone.scroll_to_mark(&parent_mark_clone, 0.0, true, 0.0, 0.5);
+                }
+                Inhibit(false)
+            });
+            buf.apply_tag(&jump_tag, &header_start, end);
+        }
+        buf.delete_mark(&header_mark);
+    }
 
-                    let buf_clone = buf.clone();
-                    let privmsg_clone = privmsg.clone();
-                    let mut til_clone = til.clone();
-                    let start_of_message_mark_clone = start_of_message_mark.clone();
-                    let mut async = move || {
-                        if let Ok(emotes) = receiver.try_recv()
-                        {
-                            let mut end_clone = buf_clone.get_end_iter();
-                            for emote in emotes
-                            {
-                                let mut emote_ranges = Vec::new();
-                                for m in privmsg_clone.message.match_indices(&emote.code)
-                                {
-                                    let uh = privmsg_clone.message.char_indices()
-                                                                  .collect::<Vec<_>>();
-                                    println!("Found match {:?}", m);
-                                    println!("PRINT UH");
-                                    println!("{:?}", uh);
-                                    let start_idx = privmsg_clone.message.char_indices()
-                                                                         .enumerate()
-                                                                         .filter(|i| (i.1).0 == m.0)
-                                                                         .next()
-                                                                         .map(|t| t.0)
-                                                                         .unwrap_or(0);
-                                    let code_len = emote.code.len();
-                                    let end_idx = start_idx + code_len - 1;
-                                    emote_ranges.push((start_idx, end_idx));
-                                }
+    /* Colors the amount of every "Cheer100" by tier and marks its prefix,
+     * to be swapped for the animated image by print_cheermotes.
+     */
+    fn mark_cheermotes(&self, buf: &gtk::TextBuffer, start_of_message_mark: &gtk::TextMark, message: &str, til: &mut TwitchImageLoader)
+        -> Vec<(Receiver<ImageResult<Vec<u8>>>, gtk::TextMark, gtk::TextMark)>
+    {
+        let tag_table = buf.get_tag_table().unwrap();
+        let mut cheer_vec = Vec::new();
 
-                                if !emote_ranges.is_empty()
-                                {
-                                    let image_receiver = til_clone.get_emote(emote.id);
-
-                                    let mut range_marks = Vec::new();
-                                    for range in emote_ranges
-                                    {
-                                        let mut start = buf_clone.get_iter_at_mark(&start_of_message_mark_clone);
-                                        let mut end = buf_clone.get_iter_at_mark(&start_of_message_mark_clone);
-                                        start.forward_chars(range.0 as i32);
-                                        end.forward_chars(range.1 as i32 + 1);
-
-                                        let start_mark = gtk::TextMark::new(None, true);
-                                        buf_clone.add_mark(&start_mark, &start);
-                                        let end_mark = gtk::TextMark::new(None, true);
-                                        buf_clone.add_mark(&end_mark, &end);
-                                        range_marks.push((start_mark, end_mark));
-                                    }
-
-                                    let buf_clone_clone = buf_clone.clone();
-                                    let range_marks_clone = range_marks.clone();
-                                    let image_async = move || {
-                                        if let Ok(bin) = image_receiver.try_recv()
-                                        {
-                                            println!("=====================LOADED BIN FOR EMOTE");
-                                            let pbl = gdk_pixbuf::PixbufLoader::new();
-                                            pbl.loader_write(&bin).unwrap();
-                                            pbl.close().unwrap();
-
-                                            if let Some(pixbuf) = pbl.get_pixbuf()
-                                            {
-                                                for range in &range_marks_clone
-                                                {
-                                                    let mut start = buf_clone_clone.get_iter_at_mark(&range.0);
-                                                    let mut end = buf_clone_clone.get_iter_at_mark(&range.1);
-                                                    buf_clone_clone.delete(&mut start, &mut end);
-                                                    buf_clone_clone.insert_pixbuf(&mut start, &pixbuf);
-                                                }
-                                            }
-                                            Continue(false)
-                                        }
-                                        else
-                                        {
-                                            Continue(true)
-                                        }
-                                    };
-
-                                    if let Continue(true) = image_async()
-                                    {
-                                        gtk::timeout_add(30, image_async);
-                                    }
-                                    else
-                                    {
-                                        end_clone = buf_clone.get_end_iter();
-                                    }
-                                }
-                            }
+        for cheer in find_cheers(message)
+        {
+            let prefix_len = cheer.prefix.chars().count();
+            let mut start = buf.get_iter_at_mark(start_of_message_mark);
+            let mut amount_start = buf.get_iter_at_mark(start_of_message_mark);
+            let mut end = buf.get_iter_at_mark(start_of_message_mark);
+            start.forward_chars(cheer.range.0 as i32);
+            amount_start.forward_chars((cheer.range.0 + prefix_len) as i32);
+            end.forward_chars(cheer.range.1 as i32);
 
-                            Continue(false)
-                        }
-                        else
-                        {
-                            Continue(true)
-                        }
-                    };
+            /* One tag per tier color, shared by every cheer */
+            let color = cheer.color();
+            let color = format!("#{:02x}{:02x}{:02x}", color.0, color.1, color.2);
+            let tag_name = format!("cheer-amount-{}", color);
+            let amount_tag = match tag_table.lookup(&tag_name)
+            {
+                Some(amount_tag) => amount_tag,
+                None =>
+                {
+                    let amount_tag = gtk::TextTag::new(Some(&tag_name));
+                    amount_tag.set_property("foreground", &color.to_value()).unwrap();
+                    amount_tag.set_property("weight", &700.to_value()).unwrap();
+                    tag_table.add(&amount_tag);
+                    amount_tag
+                },
+            };
+            buf.apply_tag(&amount_tag, &amount_start, &end);
 
-                    if let Continue(true) = async()
+            let start_mark = gtk::TextMark::new(None, true);
+            buf.add_mark(&start_mark, &start);
+            let prefix_end_mark = gtk::TextMark::new(None, true);
+            buf.add_mark(&prefix_end_mark, &amount_start);
+
+            cheer_vec.push((til.get_cheermote(&cheer), start_mark, prefix_end_mark));
+        }
+        cheer_vec
+    }
+
+    fn print_cheermotes(&self, buf: &gtk::TextBuffer, cheer_vec: Vec<(Receiver<ImageResult<Vec<u8>>>, gtk::TextMark, gtk::TextMark)>)
+    {
+        for (receiver, start_mark, prefix_end_mark) in cheer_vec
+        {
+            let buf_clone = buf.clone();
+            let backlog_clone = self.backlog.clone();
+            let alive_clone = self.alive.clone();
+            let async = move || {
+                if !alive_clone.get()
+                {
+                    return Continue(false);
+                }
+
+                let bin = match poll_image(&receiver)
+                {
+                    Some(bin) => bin,
+                    None => return Continue(true),
+                };
+
+                /* A pixbuf in the buffer would only show the first frame.
+                 * Without an image the prefix stays as text.
+                 */
+                if let Some(animation) = bin.and_then(|bin| decode_animation(&bin))
+                {
+                    let mut start = buf_clone.get_iter_at_mark(&start_mark);
+                    let mut end = buf_clone.get_iter_at_mark(&prefix_end_mark);
+                    buf_clone.delete(&mut start, &mut end);
+                    if let Some(anchor) = buf_clone.create_child_anchor(&mut start)
                     {
-                        gtk::timeout_add(30, async);
+                        let image = gtk::Image::new_from_animation(&animation);
+                        image.show();
+                        backlog_clone.add_child_at_anchor(&image, &anchor);
                     }
-                    else
+                }
+                buf_clone.delete_mark(&start_mark);
+                buf_clone.delete_mark(&prefix_end_mark);
+                Continue(false)
+            };
+
+            if let Continue(true) = async()
+            {
+                gtk::timeout_add(30, async);
+            }
+        }
+    }
+
+    pub fn reply_target(&self, user: &str) -> Option<TwitchReply>
+    {
+        self.reply_targets.get(&user.to_lowercase())
+                          .cloned()
+    }
+
+    pub fn set_room_state(&mut self, room_state: &TwitchRoomState)
+    {
+        let modes = room_state.describe();
+        self.room_state_label.set_text(&modes);
+        if modes.is_empty()
+        {
+            self.room_state_label.hide();
+        }
+        else
+        {
+            self.room_state_label.show();
+        }
+    }
+
+    pub fn clear_chat(&mut self, clear: &TwitchClearChat)
+    {
+        let buf = self.backlog.get_buffer().unwrap();
+
+        match clear.target
+        {
+            Some(ref user) =>
+            {
+                self.reply_targets.remove(user);
+                if let Some(lines) = self.user_lines.remove(user)
+                {
+                    for (start_mark, end_mark) in lines
                     {
-                        end = buf.get_end_iter();
+                        let start = buf.get_iter_at_mark(&start_mark);
+                        let end = buf.get_iter_at_mark(&end_mark);
+                        buf.apply_tag(&self.cleared_tag, &start, &end);
+                        buf.delete_mark(&start_mark);
+                        buf.delete_mark(&end_mark);
                     }
                 }
+
+                let mut line = match clear.ban_duration
+                {
+                    Some(duration) => format!("{} was timed out for {} s", user, duration),
+                    None => format!("{} was permanently banned", user),
+                };
+                if let Some(ref reason) = clear.ban_reason
+                {
+                    line = format!("{} ({})", line, reason);
+                }
+                self.println(&line);
             },
-            TwitchMessage::Unknown(raw) =>
-            {
-                buf.insert(&mut end, &raw);
-            },
-            _ =>
+            None =>
             {
-                unreachable!();
+                let (start, end) = buf.get_bounds();
+                buf.apply_tag(&self.cleared_tag, &start, &end);
+                for (_, (start_mark, end_mark)) in self.message_ranges.drain()
+                {
+                    buf.delete_mark(&start_mark);
+                    buf.delete_mark(&end_mark);
+                }
+                for (_, lines) in self.user_lines.drain()
+                {
+                    for (start_mark, end_mark) in lines
+                    {
+                        buf.delete_mark(&start_mark);
+                        buf.delete_mark(&end_mark);
+                    }
+                }
+                self.reply_targets.clear();
+                self.println("Chat was cleared by a moderator");
             },
         }
     }
 
+    pub fn delete_message(&mut self, clear: &TwitchClearMsg)
+    {
+        let buf = self.backlog.get_buffer().unwrap();
+
+        self.reply_targets.retain(|_, target| target.parent_id != clear.target_msg_id);
+        if let Some((start_mark, end_mark)) = self.message_ranges.remove(&clear.target_msg_id)
+        {
+            /* Each deleted message gets its own tag so it can be revealed on its own */
+            let hidden_tag = gtk::TextTag::new(None);
+            hidden_tag.set_property("invisible", &true.to_value()).unwrap();
+            self.add_line_tag(&buf, &hidden_tag, &buf.get_iter_at_mark(&end_mark));
+
+            let start = buf.get_iter_at_mark(&start_mark);
+            let end = buf.get_iter_at_mark(&end_mark);
+            buf.apply_tag(&hidden_tag, &start, &end);
+
+            let toggle_tag = gtk::TextTag::new(None);
+            toggle_tag.set_property("foreground", &"#808080".to_value()).unwrap();
+
+            let hidden = Cell::new(true);
+            toggle_tag.connect_event(move |_, _, event, _| {
+                if event.get_event_type() == gdk::EventType::ButtonPress
+                {
+                    hidden.set(!hidden.get());
+                    hidden_tag.set_property("invisible", &hidden.get().to_value()).unwrap();
+                }
+                Inhibit(false)
+            });
+
+            let placeholder_mark = gtk::TextMark::new(None, true);
+            let mut iter = buf.get_iter_at_mark(&start_mark);
+            buf.add_mark(&placeholder_mark, &iter);
+            buf.insert(&mut iter, "<message deleted> ");
+            self.add_line_tag(&buf, &toggle_tag, &iter);
+            let placeholder_start = buf.get_iter_at_mark(&placeholder_mark);
+            buf.apply_tag(&toggle_tag, &placeholder_start, &iter);
+
+            buf.delete_mark(&placeholder_mark);
+            buf.delete_mark(&start_mark);
+            buf.delete_mark(&end_mark);
+        }
+    }
+
     pub fn on_text<F>(&mut self, callback: F) -> u64
         where F: Fn(String, String) + 'static
     {
@@ -516,13 +1114,176 @@ impl ChannelWidget
         let name_clone = self.name.clone();
         self.refcell_data.borrow().entry.connect_activate(move |entry_ref|
             {
-                let mut internal = internal_clone.borrow_mut();
-                internal.entry_backlog[0] = entry_ref.get_text().unwrap();
-                internal.entry_backlog.push_front("".to_string());
-                internal.entry_idx.set(0);
-                println!("entry_idx: 0");
-                callback(name_clone.clone(), internal.entry.get_text().unwrap());
-                internal.entry.set_text("");
+                let text = entry_ref.get_text().unwrap();
+                {
+                    let mut internal = internal_clone.borrow_mut();
+                    internal.entry_backlog[0] = text.clone();
+                    internal.entry_backlog.push_front("".to_string());
+                    internal.entry_idx.set(0);
+                    println!("entry_idx: 0");
+                }
+                /* Not borrowed here, the callback may print to this tab */
+                callback(name_clone.clone(), text);
+                entry_ref.set_text("");
             })
     }
 }
+
+impl Drop for ChannelWidget
+{
+    fn drop(&mut self)
+    {
+        self.alive.set(false);
+    }
+}
+
+fn escape_markup(text: &str) -> String
+{
+    text.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+}
+
+/* Puts an emote in place of its code. The image goes in before the code is
+ * deleted, so marks at the end of the code, like the end of the message,
+ * stay after it.
+ */
+fn replace_with_pixbuf(buf: &gtk::TextBuffer, start_mark: &gtk::TextMark, end_mark: &gtk::TextMark, pixbuf: &gdk_pixbuf::Pixbuf)
+{
+    let mut start = buf.get_iter_at_mark(start_mark);
+    buf.insert_pixbuf(&mut start, pixbuf);
+    let mut end = buf.get_iter_at_mark(end_mark);
+    buf.delete(&mut start, &mut end);
+}
+
+/* Deletes both marks when the range ends before offset */
+fn forget_if_before(buf: &gtk::TextBuffer, start_mark: &gtk::TextMark, end_mark: &gtk::TextMark, offset: i32) -> bool
+{
+    if buf.get_iter_at_mark(end_mark).get_offset() >= offset
+    {
+        return false;
+    }
+    buf.delete_mark(start_mark);
+    buf.delete_mark(end_mark);
+    true
+}
+
+/* Cuts text down to max chars, marking the cut with "…" */
+fn ellipsize(text: &str, max: usize) -> String
+{
+    if text.chars().count() <= max
+    {
+        return String::from(text);
+    }
+    text.chars()
+        .take(max)
+        .collect::<String>() + "\u{2026}"
+}
+
+/* None while the image is still loading, then the image if there is one */
+fn poll_image<T>(receiver: &Receiver<ImageResult<T>>) -> Option<Option<T>>
+{
+    match receiver.try_recv()
+    {
+        Ok(result) => Some(result.ok()),
+        Err(TryRecvError::Empty) => None,
+        Err(TryRecvError::Disconnected) => Some(None),
+    }
+}
+
+fn decode_animation(bin: &[u8]) -> Option<gdk_pixbuf::PixbufAnimation>
+{
+    let pbl = gdk_pixbuf::PixbufLoader::new();
+    let written = pbl.loader_write(bin).is_ok();
+    if !pbl.close().is_ok() || !written
+    {
+        return None;
+    }
+    pbl.get_animation()
+}
+
+/* Widget tests need a display, and GTK may only be used from the thread
+ * that initialized it, so they all run from this one test.
+ */
+#[test]
+fn test_channel_widget()
+{
+    if gtk::init().is_err()
+    {
+        return;
+    }
+    check_unsent_from_entry();
+    check_deleted_message_ending_in_emote();
+}
+
+#[cfg(test)]
+fn backlog_text(channel: &ChannelWidget) -> String
+{
+    let buf = channel.backlog.get_buffer().unwrap();
+    buf.get_text(&buf.get_start_iter(), &buf.get_end_iter(), false).unwrap()
+}
+
+/* Sending from the entry and being told it failed, in the same tab */
+#[cfg(test)]
+fn check_unsent_from_entry()
+{
+    let channel = Rc::new(RefCell::new(ChannelWidget::new(String::from("#test"), PixbufCache::new())));
+    let channel_clone = channel.clone();
+    channel.borrow_mut().on_text(move |_, text| {
+        channel_clone.borrow_mut().print_unsent(&text, "not connected");
+    });
+
+    let entry = channel.borrow().refcell_data.borrow().entry.clone();
+    entry.set_text("hello");
+    entry.activate();
+
+    assert_eq!(entry.get_text().unwrap(), "");
+    assert!(backlog_text(&channel.borrow()).ends_with("Message not sent (not connected), click to retry: hello"));
+}
+
+/* The emote has to be hidden along with the rest of the message */
+#[cfg(test)]
+fn check_deleted_message_ending_in_emote()
+{
+    use fixture_source::FixtureSource;
+    use twitch_message::{TwitchEmoteRange, UserColor};
+    use std::sync::Arc;
+
+    /* A 1x1 grayscale PNG */
+    let png = vec![0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+                   0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x3a, 0x7e, 0x9b,
+                   0x55, 0x00, 0x00, 0x00, 0x0a, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x60, 0x00, 0x00, 0x00,
+                   0x02, 0x00, 0x01, 0x48, 0xaf, 0xa4, 0x71, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+                   0x42, 0x60, 0x82];
+    let mut source = FixtureSource::default();
+    source.images.insert(String::from("emote/25"), png);
+    let mut loader = TwitchImageLoader::with_source(Arc::new(source));
+    /* Cached, so the emote goes in while the message is printed */
+    loader.get_emote(25).recv().unwrap().unwrap();
+
+    let mut channel = ChannelWidget::new(String::from("#test"), PixbufCache::new());
+    channel.print_message(TwitchMessage::TwitchPrivmsg(TwitchPrivmsg {
+        id: String::from("abc"),
+        user: String::from("onvar"),
+        name: String::from("OnVar"),
+        emotes: vec![TwitchEmoteRange { id: 25, ranges: vec![(3, 7)] }],
+        badges: Vec::new(),
+        color: UserColor(0xFF, 0x00, 0x00),
+        to: String::from("#test"),
+        message: String::from("hi Kappa"),
+        bits: 0,
+        reply: None,
+    }), &mut loader);
+
+    let buf = channel.backlog.get_buffer().unwrap();
+    let visible = || buf.get_slice(&buf.get_start_iter(), &buf.get_end_iter(), false).unwrap();
+    assert!(visible().ends_with("OnVar: hi \u{FFFC}"));
+
+    channel.delete_message(&TwitchClearMsg {
+        channel: String::from("#test"),
+        login: String::from("onvar"),
+        target_msg_id: String::from("abc"),
+        message: String::from("hi Kappa"),
+    });
+    assert!(visible().ends_with("OnVar: <message deleted> "));
+}
@@ -1,9 +1,12 @@
 use ui::channel::ChannelWidget;
+use ui::pixbuf_cache::PixbufCache;
+use lru_cache::CacheStats;
 use twitch_image_loader::TwitchImageLoader;
 
-use twitch_message::TwitchMessage;
+use twitch_message::{TwitchMessage, TwitchReply, TwitchRoomState, TwitchClearChat, TwitchClearMsg};
 
-use twitch_chat::auth::Auth;
+use credentials::Credentials;
+use credential_store::CredentialStore;
 
 use gdk;
 use gtk;
@@ -13,12 +16,29 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::HashMap;
 
+pub const STATUS_TAB: &'static str = "Status";
+const TITLE: &'static str = "Twitch chat (By OnVar)";
+
+/* Called once a login has succeeded or failed, with the error to show */
+pub type LoginDone = Box<Fn(Result<(), String>) + 'static>;
+type LoginCallback = Rc<RefCell<Option<Box<Fn(Option<Credentials>, LoginDone) + 'static>>>>;
+
 pub struct MainWindow
 {
     notebook: gtk::Notebook,
     channels: HashMap<String, ChannelWidget>,
     status: ChannelWidget,
-    login_callback: Rc<RefCell<Option<Box<Fn(Option<Auth>) + 'static>>>>,
+    window: gtk::Window,
+    login_callback: LoginCallback,
+    accounts: Rc<RefCell<CredentialStore>>,
+    saved_menu: gtk::Menu,
+    forget_menu: gtk::Menu,
+    /* Names of the logged in accounts a tab can send as */
+    identities: Vec<String>,
+    /* Decoded images, shared by every tab */
+    pixbufs: PixbufCache,
+    close_callback: Rc<RefCell<Option<Box<Fn(String) + 'static>>>>,
+    quit_callback: Rc<RefCell<Option<Box<Fn() + 'static>>>>,
 }
 
 impl MainWindow
@@ -34,20 +54,32 @@ impl MainWindow
                 println!("Error: Could not load style sheet."),
         }
 
-        let status = ChannelWidget::new(String::from("Status"));
+        let pixbufs = PixbufCache::new();
+        let status = ChannelWidget::new(String::from(STATUS_TAB), pixbufs.clone());
 
         let menu_bar = gtk::MenuBar::new();
         let login = gtk::MenuItem::new_with_label("Login");
         let login_menu = gtk::Menu::new();
         let with_auth = gtk::MenuItem::new_with_label("With Auth");
         let anonymous = gtk::MenuItem::new_with_label("Anonymous");
+        let saved = gtk::MenuItem::new_with_label("Saved Accounts");
+        let saved_menu = gtk::Menu::new();
+        saved.set_submenu(Some(&saved_menu));
+        let forget = gtk::MenuItem::new_with_label("Forget Account");
+        let forget_menu = gtk::Menu::new();
+        forget.set_submenu(Some(&forget_menu));
+        let set_passphrase = gtk::MenuItem::new_with_label("Set Passphrase\u{2026}");
         login_menu.append(&with_auth);
         login_menu.append(&anonymous);
+        login_menu.append(&gtk::SeparatorMenuItem::new());
+        login_menu.append(&saved);
+        login_menu.append(&forget);
+        login_menu.append(&set_passphrase);
         login.set_submenu(Some(&login_menu));
         menu_bar.append(&login);
 
         let notebook = gtk::Notebook::new();
-        notebook.append_page(status.get_pane(), Some(&gtk::Label::new(Some("Status"))));
+        notebook.append_page(status.get_pane(), Some(status.get_tab_label()));
         notebook.set_scrollable(true);
 
         let main_pane = gtk::Box::new(gtk::Orientation::Vertical, 5);
@@ -55,14 +87,19 @@ impl MainWindow
         main_pane.pack_start(&notebook, true, true, 0);
 
         let win = gtk::Window::new(gtk::WindowType::Toplevel);
-        win.set_title("Twitch chat (By OnVar)");
+        win.set_title(TITLE);
         win.set_default_size(300, 500);
         win.add(&main_pane);
         
-        let login_callback: Rc<RefCell<Option<Box<Fn(Option<Auth>) + 'static>>>> = Rc::new(RefCell::new(None));
+        let login_callback: LoginCallback = Rc::new(RefCell::new(None));
+        let accounts = Rc::new(RefCell::new(CredentialStore::load()));
+        fill_account_menus(&saved_menu, &forget_menu, &accounts, &login_callback);
 
         {
             let login_callback_clone = login_callback.clone();
+            let accounts_clone = accounts.clone();
+            let saved_menu_clone = saved_menu.clone();
+            let forget_menu_clone = forget_menu.clone();
             with_auth.connect_activate(move |_| {
                 let auth_dialog = gtk::Dialog::new_with_buttons::<gtk::Window>(Some("Login"),
                                                                                None,
@@ -83,6 +120,14 @@ impl MainWindow
                 content_grid.attach(&oauth_label, 0, 1, 1, 1);
                 content_grid.attach(&oauth_entry, 1, 1, 1, 1);
 
+                let remember_check = gtk::CheckButton::new_with_label("Remember this account");
+                remember_check.set_active(true);
+                content_grid.attach(&remember_check, 1, 2, 1, 1);
+
+                let error_label = gtk::Label::new(None);
+                error_label.set_line_wrap(true);
+                content_grid.attach(&error_label, 0, 3, 2, 1);
+
                 content_area.pack_start(&content_grid, false, false, 0);
 
                 {
@@ -104,14 +149,53 @@ impl MainWindow
                 
                 /* To prevent moving login_callback_clone out of this closure */
                 let login_callback_clone_clone = login_callback_clone.clone();
+                let accounts_clone_clone = accounts_clone.clone();
+                let saved_menu_clone_clone = saved_menu_clone.clone();
+                let forget_menu_clone_clone = forget_menu_clone.clone();
                 auth_dialog.connect_response(move |dialog_ref, response_id| {
                     if response_id == 0
                     {
-                        if let Some(ref login_callback) = *login_callback_clone_clone.borrow_mut()
+                        let username = username_entry_clone.get_text().unwrap();
+                        let oauth = oauth_entry_clone.get_text().unwrap();
+                        let credentials = Credentials::new(username, oauth);
+
+                        let dialog_clone = dialog_ref.clone();
+                        let error_label_clone = error_label.clone();
+                        let remember_check_clone = remember_check.clone();
+                        let credentials_clone = credentials.clone();
+                        let accounts_clone_clone_clone = accounts_clone_clone.clone();
+                        let saved_menu_clone_clone_clone = saved_menu_clone_clone.clone();
+                        let forget_menu_clone_clone_clone = forget_menu_clone_clone.clone();
+                        let login_callback_clone_clone_clone = login_callback_clone_clone.clone();
+                        let done = Box::new(move |result: Result<(), String>| {
+                            dialog_clone.set_response_sensitive(0, true);
+                            if let Err(e) = result
+                            {
+                                /* Leave the dialog up so the token can be fixed */
+                                error_label_clone.set_text(&e);
+                                return;
+                            }
+                            if remember_check_clone.get_active() && unlock_accounts(&accounts_clone_clone_clone)
+                            {
+                                if let Err(e) = accounts_clone_clone_clone.borrow_mut().remember(credentials_clone.clone())
+                                {
+                                    println!("Error: Could not save account: {}", e);
+                                }
+                                fill_account_menus(&saved_menu_clone_clone_clone,
+                                                   &forget_menu_clone_clone_clone,
+                                                   &accounts_clone_clone_clone,
+                                                   &login_callback_clone_clone_clone);
+                            }
+                            dialog_clone.close();
+                        });
+
+                        if let Some(ref login_callback) = *login_callback_clone_clone.borrow()
                         {
-                            let username = username_entry_clone.get_text().unwrap();
-                            let oauth = oauth_entry_clone.get_text().unwrap();
-                            login_callback(Some(Auth::new(username, oauth)));
+                            /* Checking the token takes a moment */
+                            error_label.set_text("Logging in\u{2026}");
+                            dialog_ref.set_response_sensitive(0, false);
+                            login_callback(Some(credentials), done);
+                            return;
                         }
                     }
                     dialog_ref.close();
@@ -121,37 +205,100 @@ impl MainWindow
             });
         }
 
+        {
+            let accounts_clone = accounts.clone();
+            let saved_menu_clone = saved_menu.clone();
+            let forget_menu_clone = forget_menu.clone();
+            let login_callback_clone = login_callback.clone();
+            set_passphrase.connect_activate(move |_| {
+                if !unlock_accounts(&accounts_clone)
+                {
+                    return;
+                }
+                if let Some(passphrase) = ask_passphrase("Set Passphrase",
+                                                         "Saved accounts are encrypted with this passphrase. \
+                                                          Leave it empty to save them without one.",
+                                                         true)
+                {
+                    if let Err(e) = accounts_clone.borrow_mut().set_passphrase(&passphrase)
+                    {
+                        println!("Error: Could not save accounts: {}", e);
+                    }
+                }
+                fill_account_menus(&saved_menu_clone, &forget_menu_clone, &accounts_clone, &login_callback_clone);
+            });
+        }
+
         {
             let login_callback_clone = login_callback.clone();
             anonymous.connect_activate(move |_| {
                 if let Some(ref login_callback) = *login_callback_clone.borrow_mut()
                 {
-                    login_callback(None);
+                    /* Failures are logged to the status tab */
+                    login_callback(None, Box::new(|_: Result<(), String>| {}));
                 }
             });
         }
 
-        win.connect_delete_event(|_, _| {
-            gtk::main_quit();
-            Inhibit(false)
-        });
+        let quit_callback: Rc<RefCell<Option<Box<Fn() + 'static>>>> = Rc::new(RefCell::new(None));
+        {
+            let quit_callback_clone = quit_callback.clone();
+            win.connect_delete_event(move |_, _| {
+                /* While the notebook still has its tabs */
+                if let Some(ref quit_callback) = *quit_callback_clone.borrow()
+                {
+                    quit_callback();
+                }
+                gtk::main_quit();
+                Inhibit(false)
+            });
+        }
 
         win.show_all();
 
         MainWindow
         {
+            window: win,
             notebook: notebook,
             channels: HashMap::new(),
             status: status,
             login_callback: login_callback,
+            accounts: accounts,
+            saved_menu: saved_menu,
+            forget_menu: forget_menu,
+            identities: Vec::new(),
+            pixbufs: pixbufs,
+            close_callback: Rc::new(RefCell::new(None)),
+            quit_callback: quit_callback,
         }
     }
 
     pub fn add_channel<F, T>(&mut self, name: String, callback: F, ctx: T)
         where F: Fn(String, String, &mut T) + 'static, T: 'static
     {
-        let mut new_ch = ChannelWidget::new(name.clone());
-        self.notebook.append_page(new_ch.get_pane(), Some(&gtk::Label::new(Some(&name.clone()))));
+        let name = name.trim().to_lowercase();
+        let mut new_ch = ChannelWidget::new(name.clone(), self.pixbufs.clone());
+        new_ch.set_identities(&self.identities);
+
+        let tab = gtk::Box::new(gtk::Orientation::Horizontal, 4);
+        let close_button = gtk::Button::new_with_label("×");
+        close_button.set_relief(gtk::ReliefStyle::None);
+        tab.pack_start(new_ch.get_tab_label(), true, true, 0);
+        tab.pack_start(&close_button, false, false, 0);
+        tab.show_all();
+
+        {
+            let close_callback_clone = self.close_callback.clone();
+            let name_clone = name.clone();
+            close_button.connect_clicked(move |_| {
+                if let Some(ref close_callback) = *close_callback_clone.borrow()
+                {
+                    close_callback(name_clone.clone());
+                }
+            });
+        }
+
+        self.notebook.append_page(new_ch.get_pane(), Some(&tab));
         self.notebook.set_tab_reorderable(new_ch.get_pane(), true);
         let ctx_rc = Rc::new(RefCell::new(ctx));
         let ctx_rc_clone = ctx_rc.clone();
@@ -164,6 +311,125 @@ impl MainWindow
         self.notebook.show_all();
     }
 
+    /* Dropping the widget also stops its pending image callbacks */
+    pub fn remove_channel(&mut self, name: &str) -> bool
+    {
+        if let Some(channel) = self.channels.remove(&name.to_lowercase())
+        {
+            if let Some(page) = self.notebook.page_num(channel.get_pane())
+            {
+                self.notebook.remove_page(Some(page));
+            }
+            true
+        }
+        else
+        {
+            false
+        }
+    }
+
+    /* Shows who is logged in, with the token's scopes, in the title */
+    pub fn set_logged_in(&self, accounts: &[String])
+    {
+        if accounts.is_empty()
+        {
+            self.window.set_title(TITLE);
+        }
+        else
+        {
+            self.window.set_title(&format!("{} - {}", TITLE, accounts.join(", ")));
+        }
+    }
+
+    pub fn set_identities(&mut self, identities: Vec<String>)
+    {
+        for channel in self.channels.values_mut()
+        {
+            channel.set_identities(&identities);
+        }
+        self.identities = identities;
+    }
+
+    /* None for the status tab, which has no account to pick */
+    pub fn channel_identity(&self, name: &str) -> Option<String>
+    {
+        self.channels.get(&name.to_lowercase())
+                     .and_then(ChannelWidget::get_identity)
+    }
+
+    pub fn channel_set_identity(&mut self, name: &str, identity: &str)
+    {
+        if let Some(channel) = self.channel_mut(name)
+        {
+            channel.set_identity(identity);
+        }
+    }
+
+    pub fn channel_reply_target(&self, name: &str, user: &str) -> Option<TwitchReply>
+    {
+        self.channels.get(&name.to_lowercase())
+                     .and_then(|channel| channel.reply_target(user))
+    }
+
+    pub fn pixbuf_stats(&self) -> CacheStats
+    {
+        self.pixbufs.stats()
+    }
+
+    /* For unlock_accounts, which has to run without the window borrowed */
+    pub fn saved_accounts(&self) -> Rc<RefCell<CredentialStore>>
+    {
+        self.accounts.clone()
+    }
+
+    pub fn refill_account_menus(&self)
+    {
+        fill_account_menus(&self.saved_menu, &self.forget_menu, &self.accounts, &self.login_callback);
+    }
+
+    pub fn saved_account(&self, username: &str) -> Option<Credentials>
+    {
+        self.accounts.borrow()
+                     .get(username)
+                     .cloned()
+    }
+
+    pub fn has_channel(&self, name: &str) -> bool
+    {
+        self.channels.contains_key(&name.to_lowercase())
+    }
+
+    pub fn focus_channel(&mut self, name: &str)
+    {
+        if let Some(channel) = self.channels.get(&name.to_lowercase())
+        {
+            if let Some(page) = self.notebook.page_num(channel.get_pane())
+            {
+                self.notebook.set_current_page(Some(page));
+            }
+        }
+    }
+
+    /* Tab names are kept lowercase since Twitch logins are */
+    fn channel_mut(&mut self, name: &str) -> Option<&mut ChannelWidget>
+    {
+        self.channels.get_mut(&name.to_lowercase())
+    }
+
+    /* Every joined channel in tab order, leaving out whisper tabs */
+    pub fn channel_names(&self) -> Vec<String>
+    {
+        let mut pages: Vec<(u32, String)> = self.channels.iter()
+                                                         .filter(|&(name, _)| name.starts_with("#"))
+                                                         .filter_map(|(name, channel)| self.notebook.page_num(channel.get_pane())
+                                                                                                    .map(|page| (page, name.clone())))
+                                                         .collect();
+        pages.sort();
+        pages.into_iter()
+             .map(|(_, name)| name)
+             .collect()
+    }
+
     pub fn on_status_text<F, T>(&mut self, callback: F, ctx: T)
         where F: Fn(String, String, &mut T) + 'static, T: 'static
     {
@@ -177,11 +443,24 @@ impl MainWindow
     }
 
     pub fn on_login<F>(&mut self, callback: F)
-        where F: Fn(Option<Auth>) + 'static
+        where F: Fn(Option<Credentials>, LoginDone) + 'static
     {
         *self.login_callback.borrow_mut() = Some(Box::new(callback));
     }
 
+    pub fn on_close_channel<F>(&mut self, callback: F)
+        where F: Fn(String) + 'static
+    {
+        *self.close_callback.borrow_mut() = Some(Box::new(callback));
+    }
+
+    /* Called when the window is closed, before the tabs are destroyed */
+    pub fn on_quit<F>(&mut self, callback: F)
+        where F: Fn() + 'static
+    {
+        *self.quit_callback.borrow_mut() = Some(Box::new(callback));
+    }
+
     pub fn status_log_line(&mut self, line: &str)
     {
         self.status.println(line);
@@ -189,7 +468,7 @@ impl MainWindow
 
     pub fn channel_print_message(&mut self, channel: String, message: TwitchMessage, til: &mut TwitchImageLoader)
     {
-        if let Some(channel) = self.channels.get_mut(&channel)
+        if let Some(channel) = self.channel_mut(&channel)
         {
             channel.print_message(message, til);
         }
@@ -197,9 +476,223 @@ impl MainWindow
 
     pub fn channel_print_line(&mut self, channel: String, message: &str)
     {
-        if let Some(channel) = self.channels.get_mut(&channel)
+        if let Some(channel) = self.channel_mut(&channel)
         {
             channel.println(message);
         }
     }
+
+    pub fn channel_print_unsent(&mut self, channel: String, text: &str, reason: &str)
+    {
+        if let Some(channel) = self.channel_mut(&channel)
+        {
+            channel.print_unsent(text, reason);
+        }
+    }
+
+    pub fn channel_set_queued(&mut self, channel: String, queued: usize)
+    {
+        if let Some(channel) = self.channel_mut(&channel)
+        {
+            channel.set_queued(queued);
+        }
+    }
+
+    pub fn channel_mark_unread(&mut self, channel: String)
+    {
+        if let Some(channel) = self.channel_mut(&channel)
+        {
+            channel.mark_unread();
+        }
+    }
+
+    pub fn channel_set_room_state(&mut self, channel: String, room_state: &TwitchRoomState)
+    {
+        if let Some(channel) = self.channel_mut(&channel)
+        {
+            channel.set_room_state(room_state);
+        }
+    }
+
+    pub fn channel_clear_chat(&mut self, clear: TwitchClearChat)
+    {
+        if let Some(channel) = self.channel_mut(&clear.channel)
+        {
+            channel.clear_chat(&clear);
+        }
+    }
+
+    pub fn channel_delete_message(&mut self, clear: TwitchClearMsg)
+    {
+        if let Some(channel) = self.channel_mut(&clear.channel)
+        {
+            channel.delete_message(&clear);
+        }
+    }
+}
+
+/* Asks for the passphrase of the saved accounts until it is right, false if
+ * the user gave up. The dialog runs its own main loop.
+ */
+pub fn unlock_accounts(accounts: &Rc<RefCell<CredentialStore>>) -> bool
+{
+    let mut message = "The saved accounts are encrypted.";
+    while accounts.borrow().is_locked()
+    {
+        let passphrase = match ask_passphrase("Unlock Saved Accounts", message, false)
+        {
+            Some(passphrase) => passphrase,
+            None => return false,
+        };
+        if !accounts.borrow_mut().unlock(&passphrase)
+        {
+            message = "Wrong passphrase, try again.";
+        }
+    }
+    true
+}
+
+/* None if cancelled. With confirm set it has to be typed twice. */
+fn ask_passphrase(title: &str, message: &str, confirm: bool) -> Option<String>
+{
+    let dialog = gtk::Dialog::new_with_buttons::<gtk::Window>(Some(title),
+                                                              None,
+                                                              gtk::DIALOG_DESTROY_WITH_PARENT | gtk::DIALOG_MODAL,
+                                                              &[("cancel", 1), ("ok", 0)]);
+    let content_grid = gtk::Grid::new();
+
+    let message_label = gtk::Label::new(Some(message));
+    message_label.set_line_wrap(true);
+    content_grid.attach(&message_label, 0, 0, 2, 1);
+
+    let passphrase_label = gtk::Label::new(Some("Passphrase"));
+    passphrase_label.set_padding(3, 0);
+    let passphrase_entry = gtk::Entry::new();
+    passphrase_entry.set_visibility(false);
+    content_grid.attach(&passphrase_label, 0, 1, 1, 1);
+    content_grid.attach(&passphrase_entry, 1, 1, 1, 1);
+
+    let confirm_entry = gtk::Entry::new();
+    confirm_entry.set_visibility(false);
+    if confirm
+    {
+        let confirm_label = gtk::Label::new(Some("Again"));
+        content_grid.attach(&confirm_label, 0, 2, 1, 1);
+        content_grid.attach(&confirm_entry, 1, 2, 1, 1);
+    }
+
+    for entry in &[&passphrase_entry, &confirm_entry]
+    {
+        let dialog_clone = dialog.clone();
+        entry.connect_activate(move |_| {
+            dialog_clone.response(0);
+        });
+    }
+
+    dialog.get_content_area().pack_start(&content_grid, false, false, 0);
+    dialog.show_all();
+
+    loop
+    {
+        if dialog.run() != 0
+        {
+            dialog.close();
+            return None;
+        }
+        let passphrase = passphrase_entry.get_text().unwrap_or(String::new());
+        if confirm && confirm_entry.get_text().unwrap_or(String::new()) != passphrase
+        {
+            message_label.set_text("The passphrases do not match.");
+            continue;
+        }
+        dialog.close();
+        return Some(passphrase);
+    }
+}
+
+/* Rebuilds the saved account pickers after an account is added or forgotten */
+fn fill_account_menus(saved_menu: &gtk::Menu,
+                      forget_menu: &gtk::Menu,
+                      accounts: &Rc<RefCell<CredentialStore>>,
+                      login_callback: &LoginCallback)
+{
+    for menu in &[saved_menu, forget_menu]
+    {
+        for child in menu.get_children()
+        {
+            menu.remove(&child);
+        }
+    }
+
+    if accounts.borrow().is_locked()
+    {
+        let unlock_item = gtk::MenuItem::new_with_label("Unlock\u{2026}");
+        {
+            let saved_menu_clone = saved_menu.clone();
+            let forget_menu_clone = forget_menu.clone();
+            let accounts_clone = accounts.clone();
+            let login_callback_clone = login_callback.clone();
+            unlock_item.connect_activate(move |_| {
+                if unlock_accounts(&accounts_clone)
+                {
+                    fill_account_menus(&saved_menu_clone, &forget_menu_clone, &accounts_clone, &login_callback_clone);
+                }
+            });
+        }
+        saved_menu.append(&unlock_item);
+
+        let locked_item = gtk::MenuItem::new_with_label("(locked)");
+        locked_item.set_sensitive(false);
+        forget_menu.append(&locked_item);
+
+        saved_menu.show_all();
+        forget_menu.show_all();
+        return;
+    }
+
+    for account in accounts.borrow().accounts()
+    {
+        let login_item = gtk::MenuItem::new_with_label(&account.username);
+        {
+            let login_callback_clone = login_callback.clone();
+            let account_clone = account.clone();
+            login_item.connect_activate(move |_| {
+                if let Some(ref login_callback) = *login_callback_clone.borrow()
+                {
+                    login_callback(Some(account_clone.clone()), Box::new(|_: Result<(), String>| {}));
+                }
+            });
+        }
+        saved_menu.append(&login_item);
+
+        let forget_item = gtk::MenuItem::new_with_label(&account.username);
+        {
+            let saved_menu_clone = saved_menu.clone();
+            let forget_menu_clone = forget_menu.clone();
+            let accounts_clone = accounts.clone();
+            let login_callback_clone = login_callback.clone();
+            let username = account.username.clone();
+            forget_item.connect_activate(move |_| {
+                if let Err(e) = accounts_clone.borrow_mut().forget(&username)
+                {
+                    println!("Error: Could not forget account: {}", e);
+                }
+                fill_account_menus(&saved_menu_clone, &forget_menu_clone, &accounts_clone, &login_callback_clone);
+            });
+        }
+        forget_menu.append(&forget_item);
+    }
+
+    if accounts.borrow().accounts().is_empty()
+    {
+        for menu in &[saved_menu, forget_menu]
+        {
+            let none_item = gtk::MenuItem::new_with_label("(none)");
+            none_item.set_sensitive(false);
+            menu.append(&none_item);
+        }
+    }
+
+    saved_menu.show_all();
+    forget_menu.show_all();
 }
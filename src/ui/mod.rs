@@ -2,3 +2,4 @@ extern crate gtk;
 
 pub mod main_window;
 pub mod channel;
+pub mod pixbuf_cache;
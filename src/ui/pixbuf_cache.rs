@@ -0,0 +1,67 @@
+use twitch_image_loader::ImageKey;
+use lru_cache::{CacheStats, LruCache, Weigh};
+
+use gdk_pixbuf;
+use gdk_pixbuf::Pixbuf;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/* Bytes of decoded images kept for all tabs together */
+const PIXBUF_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+impl Weigh for Pixbuf
+{
+    fn weight(&self) -> usize
+    {
+        self.get_rowstride() as usize * self.get_height() as usize
+    }
+}
+
+/* Decoded badges and emotes shared by every tab, so the same image is not
+ * decoded again for every message it shows up in.
+ */
+#[derive(Clone)]
+pub struct PixbufCache
+{
+    cache: Rc<RefCell<LruCache<ImageKey, Pixbuf>>>,
+}
+
+impl PixbufCache
+{
+    pub fn new() -> PixbufCache
+    {
+        PixbufCache
+        {
+            cache: Rc::new(RefCell::new(LruCache::new(PIXBUF_CACHE_BYTES))),
+        }
+    }
+
+    /* The cached pixbuf for key, decoding bin only when there is none.
+     * None when bin is not an image gdk-pixbuf can read.
+     */
+    pub fn decode(&self, key: ImageKey, bin: &[u8]) -> Option<Pixbuf>
+    {
+        if let Some(pixbuf) = self.cache.borrow_mut().get(&key)
+        {
+            return Some(pixbuf.clone());
+        }
+
+        let pbl = gdk_pixbuf::PixbufLoader::new();
+        /* Closed even after a failed write, the loader complains otherwise */
+        let written = pbl.loader_write(bin).is_ok();
+        if !pbl.close().is_ok() || !written
+        {
+            return None;
+        }
+        pbl.get_pixbuf().map(|pixbuf| {
+            self.cache.borrow_mut().insert(key, pixbuf.clone());
+            pixbuf
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        self.cache.borrow().stats()
+    }
+}